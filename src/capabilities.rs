@@ -0,0 +1,284 @@
+use core::fmt::Write;
+use std::{
+    collections::BTreeSet,
+    path::{
+        Path,
+        PathBuf,
+    },
+};
+
+use serde::Serialize;
+use tokio::process::Command;
+
+use crate::converter::{
+    CrfMode,
+    JobSettings,
+};
+
+/// What the FFmpeg binary has been compiled with, as read at startup.
+#[derive(Debug, Clone, Serialize)]
+pub struct Capabilities {
+    /// The first line of `ffmpeg -version`.
+    pub ffmpeg_version: String,
+    /// The first line of `ffprobe -version`.
+    pub ffprobe_version: String,
+    pub encoders: BTreeSet<String>,
+    pub filters: BTreeSet<String>,
+    pub muxers: BTreeSet<String>,
+}
+
+/// Something that a job needs from FFmpeg.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(tag = "kind", content = "name", rename_all = "lowercase")]
+pub enum Requirement {
+    Encoder(&'static str),
+    Filter(&'static str),
+    Muxer(&'static str),
+}
+
+impl std::fmt::Display for Requirement {
+    fn fmt(
+        &self,
+        f: &mut std::fmt::Formatter<'_>,
+    ) -> std::fmt::Result {
+        match self {
+            Requirement::Encoder(name) => write!(f, "encoder {}", name),
+            Requirement::Filter(name) => write!(f, "filter {}", name),
+            Requirement::Muxer(name) => write!(f, "muxer {}", name),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum CapabilityError {
+    /// The binary could not be run or did not exit successfully.
+    Run(PathBuf, String),
+    /// What the default settings need is missing.
+    Missing(Vec<Requirement>),
+}
+
+impl CapabilityError {
+    pub fn write_error_msg<W>(
+        &self,
+        writer: &mut W,
+    ) -> Result<(), std::fmt::Error>
+    where
+        W: Write,
+    {
+        match self {
+            CapabilityError::Run(path, e) => {
+                write!(writer, "Unable to run \"{}\": {}", path.display(), e)
+            },
+            CapabilityError::Missing(missing) => {
+                write!(writer, "FFmpeg is missing the ")?;
+                write_requirements(writer, missing)
+            },
+        }
+    }
+
+    pub fn as_error_msg(&self) -> String {
+        let mut retval = String::new();
+        self.write_error_msg(&mut retval).unwrap();
+        retval
+    }
+}
+
+/// Write the requirements as a comma-separated list.
+pub fn write_requirements<W>(
+    writer: &mut W,
+    requirements: &[Requirement],
+) -> Result<(), std::fmt::Error>
+where
+    W: Write,
+{
+    for (idx, requirement) in requirements.iter().enumerate() {
+        if idx > 0 {
+            write!(writer, ", ")?;
+        }
+
+        write!(writer, "{}", requirement)?;
+    }
+
+    Ok(())
+}
+
+impl Capabilities {
+    /// Read the versions of both binaries and the encoders, filters and
+    /// muxers of FFmpeg.
+    pub async fn probe(
+        ffmpeg: &Path,
+        ffprobe: &Path,
+    ) -> Result<Capabilities, CapabilityError> {
+        let ffmpeg_version = run(ffmpeg, "-version").await?;
+        let ffprobe_version = run(ffprobe, "-version").await?;
+        let encoders = run(ffmpeg, "-encoders").await?;
+        let filters = run(ffmpeg, "-filters").await?;
+        let muxers = run(ffmpeg, "-muxers").await?;
+
+        Ok(Capabilities {
+            ffmpeg_version: first_line(&ffmpeg_version),
+            ffprobe_version: first_line(&ffprobe_version),
+            encoders: parse_listing(&encoders),
+            filters: parse_filters(&filters),
+            muxers: parse_listing(&muxers),
+        })
+    }
+
+    fn has(
+        &self,
+        requirement: Requirement,
+    ) -> bool {
+        match requirement {
+            Requirement::Encoder(name) => self.encoders.contains(name),
+            Requirement::Filter(name) => self.filters.contains(name),
+            Requirement::Muxer(name) => self.muxers.contains(name),
+        }
+    }
+
+    /// What a job with these settings needs that FFmpeg does not have.
+    ///
+    /// The audio-only container is only checked if the job has no video, and
+    /// the video settings of every extra target are checked if it has one.
+    pub fn missing_for(
+        &self,
+        settings: &JobSettings,
+        has_video: bool,
+    ) -> Vec<Requirement> {
+        use Requirement::*;
+
+        let mut requirements = vec![
+            Encoder("libopus"),
+            Filter("loudnorm"),
+            Filter("aformat"),
+            Filter("adelay"),
+            Filter("atrim"),
+        ];
+
+        if has_video {
+            for video in settings.all_video() {
+                // the video is encoded into a file of its own before it is
+                // muxed into the output
+                requirements.push(Muxer(video.codec.muxer()));
+                requirements.push(Encoder(video.codec.encoder()));
+
+                if video.ladder.is_empty() {
+                    let container = video.output_container();
+                    requirements.push(Muxer(container.muxer()));
+
+                    // text subtitles are converted into the only format that
+                    // the container carries
+                    if let Some(encoder) = container
+                        .text_subtitle_encoder()
+                        .filter(|_| settings.keep_subtitles)
+                    {
+                        requirements.push(Encoder(encoder));
+                    }
+                }
+
+                // odd dimensions are always scaled into even ones
+                requirements.push(Filter("scale"));
+
+                if video.crf_mode == CrfMode::Content {
+                    requirements.push(Filter("tblend"));
+                    requirements.push(Filter("signalstats"));
+                    requirements.push(Filter("metadata"));
+                }
+
+                if !video.ladder.is_empty() {
+                    requirements.push(Muxer("dash"));
+                    requirements.push(Muxer(video.segments.muxer()));
+                }
+
+                if video.burn_subtitles.is_some() {
+                    requirements.push(Filter("subtitles"));
+                }
+
+                if video.crf_mode == CrfMode::Vmaf {
+                    requirements.push(Filter("setpts"));
+                    requirements.push(Filter("libvmaf"));
+                }
+            }
+        }
+        else {
+            requirements.push(Muxer(settings.audio_only_container.muxer()));
+        }
+
+        requirements.sort();
+        requirements.dedup();
+        requirements.into_iter().filter(|r| !self.has(*r)).collect()
+    }
+
+    /// Check that jobs with the default settings can be run at all.
+    pub fn check_defaults(&self) -> Result<(), CapabilityError> {
+        let settings = JobSettings::default();
+
+        let mut missing = self.missing_for(&settings, true);
+        missing.extend(self.missing_for(&settings, false));
+        missing.sort();
+        missing.dedup();
+
+        if missing.is_empty() {
+            Ok(())
+        }
+        else {
+            Err(CapabilityError::Missing(missing))
+        }
+    }
+}
+
+async fn run(
+    binary: &Path,
+    arg: &str,
+) -> Result<String, CapabilityError> {
+    let output = Command::new(binary)
+        .arg("-hide_banner")
+        .arg(arg)
+        .output()
+        .await
+        .map_err(|e| CapabilityError::Run(binary.to_owned(), e.to_string()))?;
+
+    if !output.status.success() {
+        return Err(CapabilityError::Run(
+            binary.to_owned(),
+            format!("`{}` exited with {}", arg, output.status),
+        ));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+fn first_line(output: &str) -> String {
+    output.lines().next().unwrap_or_default().trim().to_owned()
+}
+
+/// Parse the names of `-encoders` or `-muxers`, which are listed after a line
+/// of dashes as the second column.
+fn parse_listing(output: &str) -> BTreeSet<String> {
+    output
+        .lines()
+        .skip_while(|l| {
+            l.trim().is_empty() || !l.trim().chars().all(|c| c == '-')
+        })
+        .skip(1)
+        .filter_map(|l| l.split_whitespace().nth(1))
+        // muxers can have several names, like `mov,mp4`
+        .flat_map(|names| names.split(','))
+        .map(str::to_owned)
+        .collect()
+}
+
+/// Parse the names of `-filters`, whose lines have the inputs and outputs of
+/// the filter, like `A->A`, as the third column.
+fn parse_filters(output: &str) -> BTreeSet<String> {
+    output
+        .lines()
+        .filter_map(|l| {
+            let mut columns = l.split_whitespace();
+            let _flags = columns.next()?;
+            let name = columns.next()?;
+            let io = columns.next()?;
+
+            io.contains("->").then(|| name.to_owned())
+        })
+        .collect()
+}
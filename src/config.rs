@@ -0,0 +1,345 @@
+use core::fmt::Write;
+use std::{
+    net::SocketAddr,
+    path::{
+        Path,
+        PathBuf,
+    },
+    time::Duration,
+};
+
+use clap::Parser;
+use serde::{
+    Deserialize,
+    Deserializer,
+};
+
+/// Command line flags. Each flag can also be given through its environment
+/// variable, with the flag taking precedence.
+#[derive(Debug, Parser)]
+#[command(about = "A docker-run video converter")]
+struct Args {
+    /// Path of the configuration file. Defaults to `./undyne.toml` if it
+    /// exists.
+    #[arg(long, env = "UNDYNE_CONFIG")]
+    config: Option<PathBuf>,
+    /// Address and port to listen on, like `127.0.0.1:8080`.
+    #[arg(long, env = "UNDYNE_BIND")]
+    bind: Option<SocketAddr>,
+    /// Directory in which each job gets its own directory for its uploads,
+    /// intermediate files and output.
+    #[arg(long, env = "UNDYNE_WORK_DIR")]
+    work_dir: Option<PathBuf>,
+    /// Path of the FFmpeg binary, or its name on the PATH.
+    #[arg(long, env = "UNDYNE_FFMPEG")]
+    ffmpeg: Option<PathBuf>,
+    /// Path of the FFprobe binary, or its name on the PATH.
+    #[arg(long, env = "UNDYNE_FFPROBE")]
+    ffprobe: Option<PathBuf>,
+    /// Path of the presets file. Defaults to `./presets.json` if it exists.
+    #[arg(long, env = "UNDYNE_PRESETS")]
+    presets: Option<PathBuf>,
+    /// Maximum size of an upload request, in bytes, optionally suffixed with
+    /// `K`, `M` or `G`.
+    #[arg(long, env = "UNDYNE_MAX_UPLOAD_SIZE", value_parser = parse_size)]
+    max_upload_size: Option<usize>,
+    /// Maximum number of jobs converting at the same time.
+    #[arg(long, env = "UNDYNE_CONCURRENCY")]
+    concurrency: Option<usize>,
+    /// How long, in seconds, finished jobs and their files are kept.
+    #[arg(long, env = "UNDYNE_RETENTION")]
+    retention: Option<u64>,
+}
+
+/// The configuration file, with the same settings as the flags.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileConfig {
+    bind: Option<SocketAddr>,
+    work_dir: Option<PathBuf>,
+    ffmpeg: Option<PathBuf>,
+    ffprobe: Option<PathBuf>,
+    presets: Option<PathBuf>,
+    #[serde(default, deserialize_with = "size")]
+    max_upload_size: Option<usize>,
+    concurrency: Option<usize>,
+    retention: Option<u64>,
+}
+
+/// The configuration of the server, resolved from the flags, the environment
+/// variables and the configuration file, in that order of precedence.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub bind: SocketAddr,
+    pub work_dir: PathBuf,
+    pub ffmpeg: PathBuf,
+    pub ffprobe: PathBuf,
+    /// The presets file, if there is one.
+    pub presets: Option<PathBuf>,
+    /// The maximum size of an upload request, in bytes.
+    pub max_upload_size: usize,
+    /// The maximum number of jobs converting at the same time.
+    pub concurrency: usize,
+    /// How long finished jobs and their files are kept.
+    pub retention: Duration,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    ReadFile(PathBuf, std::io::Error),
+    ParseFile(PathBuf, toml::de::Error),
+    WorkDir(PathBuf, std::io::Error),
+    NoSuchBinary(&'static str, PathBuf),
+    NoSuchPresets(PathBuf),
+    MaxUploadSize,
+    Concurrency,
+}
+
+impl ConfigError {
+    pub fn write_error_msg<W>(
+        &self,
+        writer: &mut W,
+    ) -> Result<(), std::fmt::Error>
+    where
+        W: Write,
+    {
+        use ConfigError::*;
+
+        match self {
+            ReadFile(path, e) => {
+                write!(
+                    writer,
+                    "Unable to read configuration file \"{}\": {}",
+                    path.display(),
+                    e
+                )
+            },
+            ParseFile(path, e) => {
+                write!(
+                    writer,
+                    "Unable to parse configuration file \"{}\": {}",
+                    path.display(),
+                    e
+                )
+            },
+            WorkDir(path, e) => {
+                write!(
+                    writer,
+                    "Unable to use \"{}\" as the work directory: {}",
+                    path.display(),
+                    e
+                )
+            },
+            NoSuchBinary(name, path) => {
+                write!(
+                    writer,
+                    "Unable to find the {} binary at \"{}\"",
+                    name,
+                    path.display()
+                )
+            },
+            NoSuchPresets(path) => {
+                write!(
+                    writer,
+                    "Unable to find the presets file \"{}\"",
+                    path.display()
+                )
+            },
+            MaxUploadSize => {
+                write!(writer, "The maximum upload size must not be zero")
+            },
+            Concurrency => {
+                write!(writer, "The job concurrency must not be zero")
+            },
+        }
+    }
+
+    pub fn as_error_msg(&self) -> String {
+        let mut retval = String::new();
+        self.write_error_msg(&mut retval).unwrap();
+        retval
+    }
+}
+
+impl Config {
+    /// Read the configuration from the command line, the environment and the
+    /// configuration file, and check that it is usable.
+    ///
+    /// Invalid flags or environment variables exit the process through clap.
+    pub fn load() -> Result<Config, ConfigError> {
+        let args = Args::parse();
+
+        let file = match &args.config {
+            Some(path) => read_file_config(path)?,
+            None if Path::new("./undyne.toml").exists() => {
+                read_file_config("./undyne.toml")?
+            },
+            None => FileConfig::default(),
+        };
+
+        let presets = match args.presets.or(file.presets) {
+            Some(path) if !path.is_file() => {
+                return Err(ConfigError::NoSuchPresets(path))
+            },
+            Some(path) => Some(path),
+            None => {
+                Some(PathBuf::from("./presets.json")).filter(|p| p.is_file())
+            },
+        };
+
+        let config = Config {
+            bind: args
+                .bind
+                .or(file.bind)
+                .unwrap_or_else(|| SocketAddr::from(([127, 0, 0, 1], 8080))),
+            work_dir: args
+                .work_dir
+                .or(file.work_dir)
+                .unwrap_or_else(|| "./work".into()),
+            ffmpeg: args
+                .ffmpeg
+                .or(file.ffmpeg)
+                .unwrap_or_else(|| "ffmpeg".into()),
+            ffprobe: args
+                .ffprobe
+                .or(file.ffprobe)
+                .unwrap_or_else(|| "ffprobe".into()),
+            presets,
+            max_upload_size: args
+                .max_upload_size
+                .or(file.max_upload_size)
+                .unwrap_or(4 << 30),
+            concurrency: args.concurrency.or(file.concurrency).unwrap_or(1),
+            retention: Duration::from_secs(
+                args.retention.or(file.retention).unwrap_or(24 * 60 * 60),
+            ),
+        };
+
+        config.check()?;
+        Ok(config)
+    }
+
+    fn check(&self) -> Result<(), ConfigError> {
+        if self.max_upload_size == 0 {
+            return Err(ConfigError::MaxUploadSize);
+        }
+
+        if self.concurrency == 0 {
+            return Err(ConfigError::Concurrency);
+        }
+
+        std::fs::create_dir_all(&self.work_dir)
+            .map_err(|e| ConfigError::WorkDir(self.work_dir.clone(), e))?;
+
+        for (name, path) in
+            [("FFmpeg", &self.ffmpeg), ("FFprobe", &self.ffprobe)]
+        {
+            if !binary_exists(path) {
+                return Err(ConfigError::NoSuchBinary(name, path.clone()));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn read_file_config(path: impl AsRef<Path>) -> Result<FileConfig, ConfigError> {
+    let path = path.as_ref();
+
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| ConfigError::ReadFile(path.to_owned(), e))?;
+    toml::from_str(&contents)
+        .map_err(|e| ConfigError::ParseFile(path.to_owned(), e))
+}
+
+/// Whether the binary exists, either at the path or, for bare names, in one of
+/// the directories of the PATH.
+fn binary_exists(path: &Path) -> bool {
+    if path.components().count() > 1 {
+        return path.is_file();
+    }
+
+    std::env::var_os("PATH")
+        .map(|paths| {
+            std::env::split_paths(&paths).any(|p| p.join(path).is_file())
+        })
+        .unwrap_or(false)
+}
+
+/// Parse a size in bytes, optionally suffixed with `K`, `M` or `G`.
+pub(crate) fn parse_size(value: &str) -> Result<usize, String> {
+    let (number, shift) = match value.char_indices().last() {
+        Some((idx, 'K' | 'k')) => (&value[.. idx], 10),
+        Some((idx, 'M' | 'm')) => (&value[.. idx], 20),
+        Some((idx, 'G' | 'g')) => (&value[.. idx], 30),
+        _ => (value, 0),
+    };
+
+    number
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_mul(1 << shift))
+        .ok_or_else(|| {
+            format!(
+                "invalid size \"{}\", expected bytes optionally suffixed with \
+                 \"K\", \"M\" or \"G\"",
+                value
+            )
+        })
+}
+
+pub(crate) fn size<'de, D>(deserializer: D) -> Result<Option<usize>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    // sizes can be given as a number or as a string with a suffix
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Size {
+        Number(usize),
+        String(String),
+    }
+
+    match Option::<Size>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(Size::Number(n)) => Ok(Some(n)),
+        Some(Size::String(s)) => {
+            parse_size(&s).map(Some).map_err(serde::de::Error::custom)
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_size;
+
+    #[test]
+    fn parse_size_reads_plain_bytes() {
+        assert_eq!(parse_size("0"), Ok(0));
+        assert_eq!(parse_size("1500"), Ok(1500));
+    }
+
+    #[test]
+    fn parse_size_reads_binary_suffixes() {
+        assert_eq!(parse_size("2K"), Ok(2 << 10));
+        assert_eq!(parse_size("2k"), Ok(2 << 10));
+        assert_eq!(parse_size("25M"), Ok(25 << 20));
+        assert_eq!(parse_size("1G"), Ok(1 << 30));
+    }
+
+    #[test]
+    fn parse_size_rejects_malformed_sizes() {
+        for value in ["", "K", "1.5M", "-1", "10KB", "10 M", "1T"] {
+            assert!(parse_size(value).is_err(), "{} was accepted", value);
+        }
+    }
+
+    #[test]
+    fn parse_size_rejects_overflowing_sizes() {
+        assert_eq!(parse_size(&usize::MAX.to_string()), Ok(usize::MAX));
+        assert!(parse_size(&format!("{}0", usize::MAX)).is_err());
+        assert!(parse_size(&format!("{}K", usize::MAX >> 10)).is_ok());
+        assert!(parse_size(&format!("{}K", (usize::MAX >> 10) + 1)).is_err());
+        assert!(parse_size(&format!("{}G", usize::MAX)).is_err());
+    }
+}
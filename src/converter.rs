@@ -1,36 +1,65 @@
-use core::mem::drop;
+use core::{
+    fmt::Write,
+    mem::drop,
+};
 use std::{
-    collections::VecDeque,
+    collections::{
+        BTreeMap,
+        HashMap,
+        VecDeque,
+    },
     ffi::OsStr,
-    path::PathBuf,
-    sync::Arc,
+    path::{
+        Path,
+        PathBuf,
+    },
+    process::Output,
+    sync::{
+        Arc,
+        Mutex,
+    },
 };
 
 use serde::{
     Deserialize,
     Deserializer,
+    Serialize,
+    Serializer,
 };
 use tokio::{
     join,
     process::Command,
-    select,
     sync::mpsc::{
         unbounded_channel,
-        UnboundedReceiver,
         UnboundedSender,
     },
+    try_join,
 };
 
-use crate::overseer::{
-    AudioVideoStatus,
-    JobToOverseerMessage,
-    RequestForJobStatus,
+use crate::{
+    layout::{
+        AudioSource,
+        LayoutCase,
+        OutputLayout,
+        SubtitleSource,
+    },
+    overseer::{
+        AudioVideoStatus,
+        JobToOverseerMessage,
+    },
+    probe::{
+        determine_metadata,
+        AudioStreamInfo,
+        FileMetadata,
+        ProbedInput,
+        SubtitleStreamInfo,
+    },
 };
 
 //////// Audio Section /////////////////////////////////////////////////////////
 
 /// Audio constants produced by FFmpeg
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct AudioConstants {
     input_i: f64,
     input_tp: f64,
@@ -101,37 +130,614 @@ impl<'de> Deserialize<'de> for AudioConstants {
     }
 }
 
-/// Use FFmpeg to read the audio constants of a file.
+/// Settings on how the audio tracks of a job are converted.
+#[derive(Debug, Clone)]
+pub struct AudioSettings {
+    /// The integrated loudness, in LUFS, to normalize the tracks into.
+    pub target_i: f64,
+    /// The maximum true peak, in dBTP, of the normalized tracks.
+    pub target_tp: f64,
+    /// How far off, in LU, a track's measured loudness can be from the target
+    /// before it gets normalized. If `None`, all tracks are normalized.
+    pub tolerance: Option<f64>,
+    /// Whether the loudness is baked into the audio or left to the player.
+    pub loudness_mode: LoudnessMode,
+    /// Opus encoder settings applied to all tracks.
+    pub opus: OpusSettings,
+    /// Opus encoder settings for specific tracks, overriding `opus`.
+    pub track_opus: HashMap<usize, OpusSettings>,
+    /// Metadata, dispositions and codecs requested for specific tracks.
+    pub track_options: HashMap<usize, AudioTrackOptions>,
+    /// Whether to add a stereo downmix after each surround track. The first
+    /// downmix is marked as the default track.
+    pub stereo_downmix: bool,
+    /// How much, in seconds, the audio of an uploaded file is delayed by. A
+    /// negative offset cuts off the start of the audio instead.
+    pub input_offsets: HashMap<usize, f64>,
+    /// How far off, in seconds, the duration of an audio track from a file
+    /// other than the video's can be from the video's duration.
+    pub duration_tolerance: f64,
+}
+
+impl Default for AudioSettings {
+    fn default() -> AudioSettings {
+        AudioSettings {
+            target_i: -18.,
+            target_tp: -2.,
+            tolerance: None,
+            loudness_mode: LoudnessMode::Normalize,
+            opus: OpusSettings::default(),
+            track_opus: HashMap::new(),
+            track_options: HashMap::new(),
+            stereo_downmix: false,
+            input_offsets: HashMap::new(),
+            duration_tolerance: 1.,
+        }
+    }
+}
+
+impl AudioSettings {
+    /// The Opus encoder settings of a track, with the job's settings used for
+    /// everything that the track does not override.
+    fn opus_for(
+        &self,
+        track: usize,
+    ) -> OpusSettings {
+        match self.track_opus.get(&track) {
+            Some(track_opus) => track_opus.or(&self.opus),
+            None => self.opus.clone(),
+        }
+    }
+}
+
+/// Options of a specific output audio track. Unset fields are left to the
+/// converter.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AudioTrackOptions {
+    /// The language of the track, as an ISO 639-2 code or a BCP 47 tag.
+    pub language: Option<String>,
+    pub title: Option<String>,
+    /// Whether the track is marked as a default track. Once any track is
+    /// explicitly marked, no other track is marked automatically.
+    pub default: Option<bool>,
+    pub codec: Option<AudioCodec>,
+}
+
+impl AudioTrackOptions {
+    /// Fill the unset fields of these options with those of `fallback`.
+    pub fn or(
+        &self,
+        fallback: &AudioTrackOptions,
+    ) -> AudioTrackOptions {
+        AudioTrackOptions {
+            language: self.language.clone().or(fallback.language.clone()),
+            title: self.title.clone().or(fallback.title.clone()),
+            default: self.default.or(fallback.default),
+            codec: self.codec.or(fallback.codec),
+        }
+    }
+}
+
+/// How an audio track is to be encoded.
+#[derive(
+    Debug, Copy, Clone, Default, PartialEq, Eq, Deserialize, Serialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum AudioCodec {
+    /// Copy Opus tracks when possible, otherwise encode into Opus.
+    #[default]
+    Auto,
+    /// Always encode into Opus.
+    Opus,
+    /// Copy the track as-is if it is already in Opus, without normalizing
+    /// it. Tracks that are not in Opus or have an offset are still encoded.
+    Copy,
+}
+
+/// Settings for the Opus encoder. Unset fields use the encoder's defaults.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct OpusSettings {
+    /// The target bitrate, in bits per second.
+    pub bitrate: Option<u32>,
+    pub vbr: Option<OpusVbr>,
+    pub application: Option<OpusApplication>,
+    /// Whether to downmix the track into stereo before encoding.
+    pub downmix: Option<bool>,
+}
+
+impl OpusSettings {
+    /// Fill the unset fields of these settings with those of `fallback`.
+    pub fn or(
+        &self,
+        fallback: &OpusSettings,
+    ) -> OpusSettings {
+        OpusSettings {
+            bitrate: self.bitrate.or(fallback.bitrate),
+            vbr: self.vbr.or(fallback.vbr),
+            application: self.application.or(fallback.application),
+            downmix: self.downmix.or(fallback.downmix),
+        }
+    }
+
+    fn is_downmixed(&self) -> bool {
+        self.downmix.unwrap_or(false)
+    }
+}
+
+/// The `-vbr` modes of libopus.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OpusVbr {
+    Off,
+    On,
+    Constrained,
+}
+
+impl OpusVbr {
+    fn as_arg(self) -> &'static str {
+        match self {
+            OpusVbr::Off => "off",
+            OpusVbr::On => "on",
+            OpusVbr::Constrained => "constrained",
+        }
+    }
+}
+
+/// The `-application` modes of libopus.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OpusApplication {
+    Audio,
+    Voip,
+    LowDelay,
+}
+
+impl OpusApplication {
+    fn as_arg(self) -> &'static str {
+        match self {
+            OpusApplication::Audio => "audio",
+            OpusApplication::Voip => "voip",
+            OpusApplication::LowDelay => "lowdelay",
+        }
+    }
+}
+
+/// An audio track of the output and where it comes from.
+#[derive(Debug, Clone, Serialize)]
+pub struct AudioTrackPlan {
+    /// The audio stream of the uploaded files that the track is made from.
+    pub source: AudioSource,
+    /// How much, in seconds, the track is delayed by.
+    pub offset: f64,
+    /// The Opus encoder settings of the track.
+    pub opus: OpusSettings,
+    /// Whether the track is a stereo downmix added after a surround track.
+    pub compatibility_downmix: bool,
+    /// Whether the track is marked as a default audio track.
+    pub default: bool,
+    pub language: Option<String>,
+    pub title: Option<String>,
+    pub codec: AudioCodec,
+}
+
+impl AudioTrackPlan {
+    /// Plan the output audio tracks from the layout of the output.
+    ///
+    /// Without a stereo downmix, the output tracks are the same as those of
+    /// the layout, with the first one being the default unless any track has
+    /// been explicitly marked as one.
+    fn plan(
+        layout: &OutputLayout,
+        inputs: &[ProbedInput],
+        settings: &AudioSettings,
+    ) -> Vec<AudioTrackPlan> {
+        let mut tracks: Vec<AudioTrackPlan> = vec![];
+
+        // concatenations have been rejected before the job has been made
+        for source in layout.audio.iter().filter_map(|s| s.first().copied()) {
+            let offset = settings
+                .input_offsets
+                .get(&source.file)
+                .copied()
+                .unwrap_or(0.);
+
+            tracks.push(AudioTrackPlan {
+                source,
+                offset,
+                opus: settings.opus_for(tracks.len()),
+                compatibility_downmix: false,
+                default: false,
+                language: None,
+                title: None,
+                codec: AudioCodec::Auto,
+            });
+
+            let channels = stream_info(inputs, source).map(|s| s.channels);
+            if settings.stereo_downmix && channels.unwrap_or(0) > 2 {
+                let mut opus = settings.opus_for(tracks.len());
+                opus.downmix = Some(true);
+
+                tracks.push(AudioTrackPlan {
+                    source,
+                    offset,
+                    opus,
+                    compatibility_downmix: true,
+                    default: false,
+                    language: None,
+                    title: None,
+                    codec: AudioCodec::Auto,
+                });
+            }
+        }
+
+        let explicit_default = settings
+            .track_options
+            .iter()
+            .any(|(idx, o)| *idx < tracks.len() && o.default == Some(true));
+
+        if !explicit_default {
+            let default = tracks
+                .iter()
+                .position(|t| t.compatibility_downmix)
+                .unwrap_or(0);
+            if let Some(track) = tracks.get_mut(default) {
+                track.default = true;
+            }
+        }
+
+        for (idx, track) in tracks.iter_mut().enumerate() {
+            if let Some(options) = settings.track_options.get(&idx) {
+                track.default = options.default.unwrap_or(track.default);
+                track.language = options.language.clone();
+                track.title = options.title.clone();
+                track.codec = options.codec.unwrap_or_default();
+            }
+        }
+
+        tracks
+    }
+
+    /// The bitrate that the track is expected to be encoded at, in bits per
+    /// second.
+    ///
+    /// Without a requested bitrate, this is the default of libopus: 64 kb/s
+    /// for each stream plus 32 kb/s for each coupled stream. Tracks that are
+    /// copied keep the probed bitrate of their stream. Whether a normalized
+    /// track is copied depends on its loudness, so it is expected at the
+    /// higher of the two.
+    fn expected_bitrate(
+        &self,
+        inputs: &[ProbedInput],
+        settings: &AudioSettings,
+    ) -> u64 {
+        let stream = stream_info(inputs, self.source);
+        let encoded = self.encoded_bitrate(stream);
+
+        let copied = stream
+            .filter(|s| s.codec_name == "opus" && self.offset == 0.)
+            .and_then(|s| s.bitrate());
+        let copied = match copied {
+            Some(copied) => copied,
+            None => return encoded,
+        };
+
+        if self.codec == AudioCodec::Copy {
+            return copied;
+        }
+        if self.codec == AudioCodec::Opus
+            || self.opus != OpusSettings::default()
+        {
+            return encoded;
+        }
+
+        match (settings.loudness_mode, settings.tolerance) {
+            (LoudnessMode::Normalize, None) => encoded,
+            (LoudnessMode::Normalize, Some(_)) => copied.max(encoded),
+            _ => copied,
+        }
+    }
+
+    /// The bitrate that the track is expected to be encoded into Opus at, in
+    /// bits per second.
+    fn encoded_bitrate(
+        &self,
+        stream: Option<&AudioStreamInfo>,
+    ) -> u64 {
+        if let Some(bitrate) = self.opus.bitrate {
+            return bitrate as u64;
+        }
+
+        let channels = stream.map(|s| s.channels).unwrap_or(2);
+        let channels = if self.opus.is_downmixed() {
+            channels.min(2)
+        }
+        else {
+            channels.min(8)
+        };
+
+        // the streams and coupled streams of each channel count, as per
+        // RFC 7845
+        let (streams, coupled) = match channels {
+            0 | 1 => (1, 0),
+            2 => (1, 1),
+            3 => (2, 1),
+            4 => (2, 2),
+            5 => (3, 2),
+            6 => (4, 2),
+            7 => (5, 2),
+            _ => (5, 3),
+        };
+
+        64_000 * streams + 32_000 * coupled
+    }
+
+    /// The filter that applies the offset of the track, if it has any.
+    fn offset_filter(&self) -> Option<String> {
+        if self.offset > 0. {
+            let delay = (self.offset * 1000.).round() as u64;
+            Some(format!("adelay=delays={}:all=1", delay))
+        }
+        else if self.offset < 0. {
+            Some(format!("atrim=start={},asetpts=PTS-STARTPTS", -self.offset))
+        }
+        else {
+            None
+        }
+    }
+}
+
+/// The information of the audio stream that a source refers to.
+fn stream_info(
+    inputs: &[ProbedInput],
+    source: AudioSource,
+) -> Option<&AudioStreamInfo> {
+    inputs
+        .get(source.file)
+        .and_then(|input| input.audio_streams.get(source.stream))
+}
+
+/// How a track's channels are laid out for libopus to accept them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct OpusLayout {
+    /// A filter that converts the track into the layout, if it has to be.
+    filter: Option<String>,
+    /// The channel mapping family, as per RFC 7845.
+    mapping_family: u8,
+}
+
+impl OpusLayout {
+    /// The layouts that libopus accepts with mapping family 1, in the same
+    /// order as their channel counts, starting from three channels.
+    const SURROUND_LAYOUTS: [&'static str; 6] =
+        ["3.0", "quad", "5.0", "5.1", "6.1", "7.1"];
+
+    /// Determine the layout for a track given its stream information.
+    ///
+    /// Layouts libopus does not accept, like 5.1(side), are converted into the
+    /// accepted layout with the same number of channels. Tracks with more than
+    /// eight channels are brought down into 7.1.
+    fn for_stream(
+        stream: Option<&AudioStreamInfo>,
+        downmix: bool,
+    ) -> OpusLayout {
+        let channels = stream.map(|s| s.channels).unwrap_or(2);
+        let layout = stream.and_then(|s| s.channel_layout.as_deref());
+
+        if downmix && channels > 2 {
+            return OpusLayout {
+                filter: Some("aformat=channel_layouts=stereo".to_owned()),
+                mapping_family: 0,
+            };
+        }
+
+        if channels <= 2 {
+            return OpusLayout {
+                filter: None,
+                mapping_family: 0,
+            };
+        }
+
+        let filter = match layout {
+            Some(l) if Self::SURROUND_LAYOUTS.contains(&l) => None,
+            _ => {
+                let target = Self::SURROUND_LAYOUTS[channels.min(8) - 3];
+                Some(format!("aformat=channel_layouts={}", target))
+            },
+        };
+
+        OpusLayout {
+            filter,
+            mapping_family: 1,
+        }
+    }
+}
+
+/// How the loudness of the audio tracks is handled.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+pub enum LoudnessMode {
+    /// Apply the gain into the audio itself through the loudnorm filter.
+    #[serde(rename = "normalize")]
+    Normalize,
+    /// Leave the audio as it is and write Opus `R128_TRACK_GAIN` tags.
+    #[serde(rename = "r128")]
+    R128Tags,
+    /// Leave the audio as it is and write `REPLAYGAIN_TRACK_*` tags.
+    #[serde(rename = "replaygain")]
+    ReplayGainTags,
+}
+
+/// The gain computed for a track whose loudness is left to the player.
+#[derive(Debug, Copy, Clone, Serialize)]
+pub struct AudioGain {
+    /// The gain, in dB, that brings the track to the loudness that the tags
+    /// of the mode are relative to.
+    pub gain_db: f64,
+    /// The measured true peak of the track, as a linear amplitude.
+    pub peak: f64,
+}
+
+impl AudioGain {
+    /// The loudness that `R128_TRACK_GAIN` is relative to, as per RFC 7845.
+    const R128_REFERENCE_I: f64 = -23.;
+
+    /// Compute the gain of a track given its measured constants.
+    ///
+    /// Returns `None` if the loudness is normalized instead of tagged.
+    fn compute(
+        constants: &AudioConstants,
+        settings: &AudioSettings,
+    ) -> Option<AudioGain> {
+        let reference_i = match settings.loudness_mode {
+            LoudnessMode::Normalize => return None,
+            LoudnessMode::R128Tags => Self::R128_REFERENCE_I,
+            LoudnessMode::ReplayGainTags => settings.target_i,
+        };
+
+        Some(AudioGain {
+            gain_db: reference_i - constants.input_i,
+            peak: 10f64.powf(constants.input_tp / 20.),
+        })
+    }
+
+    /// The tags to be written into the track's metadata.
+    fn tags(
+        &self,
+        mode: LoudnessMode,
+    ) -> Vec<(&'static str, String)> {
+        match mode {
+            LoudnessMode::Normalize => vec![],
+
+            // R128 gains are stored as Q7.8 fixed point numbers
+            LoudnessMode::R128Tags => {
+                let q78 = (self.gain_db * 256.)
+                    .round()
+                    .clamp(i16::MIN as f64, i16::MAX as f64)
+                    as i16;
+
+                vec![("R128_TRACK_GAIN", q78.to_string())]
+            },
+
+            LoudnessMode::ReplayGainTags => {
+                vec![
+                    (
+                        "REPLAYGAIN_TRACK_GAIN",
+                        format!("{:+.2} dB", self.gain_db),
+                    ),
+                    ("REPLAYGAIN_TRACK_PEAK", format!("{:.6}", self.peak)),
+                ]
+            },
+        }
+    }
+}
+
+/// What has been decided to be done on an audio track.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AudioTrackDecision {
+    /// The track is normalized with loudnorm and encoded into Opus.
+    Normalize,
+    /// The track is already within tolerance and is only encoded into Opus.
+    Transcode,
+    /// The track is already within tolerance and is already in Opus.
+    StreamCopy,
+}
+
+impl AudioTrackDecision {
+    /// Decide what to do on a track given its measured constants and codec.
+    ///
+    /// The true peak is treated as a ceiling: a track whose peak is well below
+    /// the target is still considered to be within tolerance.
+    ///
+    /// Opus tracks are only copied if no encoder settings nor offsets are
+    /// requested for them, since those can only be applied by encoding again.
+    /// Tracks requested to be copied skip the encoder settings and loudness
+    /// checks, and tracks requested in Opus are never copied.
+    fn decide(
+        constants: &AudioConstants,
+        stream: Option<&AudioStreamInfo>,
+        track: &AudioTrackPlan,
+        settings: &AudioSettings,
+    ) -> AudioTrackDecision {
+        let is_opus = stream.map(|s| s.codec_name == "opus").unwrap_or(false);
+        if track.codec == AudioCodec::Copy && is_opus && track.offset == 0. {
+            return AudioTrackDecision::StreamCopy;
+        }
+
+        let copyable = track.codec != AudioCodec::Opus
+            && track.opus == OpusSettings::default()
+            && track.offset == 0.;
+        let codec = if copyable {
+            stream.map(|s| s.codec_name.as_str())
+        }
+        else {
+            None
+        };
+
+        // the loudness is left to the player, so the audio is left as is
+        if settings.loudness_mode != LoudnessMode::Normalize {
+            return match codec {
+                Some("opus") => AudioTrackDecision::StreamCopy,
+                _ => AudioTrackDecision::Transcode,
+            };
+        }
+
+        let tolerance = match settings.tolerance {
+            None => return AudioTrackDecision::Normalize,
+            Some(t) => t,
+        };
+
+        let within_i =
+            (constants.input_i - settings.target_i).abs() <= tolerance;
+        let within_tp = constants.input_tp <= settings.target_tp + tolerance;
+
+        match (within_i && within_tp, codec) {
+            (false, _) => AudioTrackDecision::Normalize,
+            (true, Some("opus")) => AudioTrackDecision::StreamCopy,
+            (true, _) => AudioTrackDecision::Transcode,
+        }
+    }
+}
+
+/// Use FFmpeg to read the audio constants of the planned tracks of a file.
 ///
-/// The number of elements in the vec is the same as the number of audio tracks
-/// in a container.
+/// The number of elements in the vec is the same as the number of planned
+/// tracks. Tracks that are to be downmixed are measured after being
+/// downmixed, since their loudness differs from that of their source.
 async fn determine_audio_constants(
-    path: impl AsRef<OsStr>
-) -> Vec<AudioConstants> {
+    inputs: &[ProbedInput],
+    tracks: &[AudioTrackPlan],
+    env: &JobEnvironment,
+) -> Result<Vec<AudioConstants>, JobError> {
     let mut constants = vec![];
 
-    for unbounded_channel_no in 0 .. {
-        let audio_stats = Command::new("ffmpeg")
+    for (idx, track) in tracks.iter().enumerate() {
+        let filter_graph = if track.opus.is_downmixed() {
+            "aformat=channel_layouts=stereo,loudnorm=print_format=json"
+        }
+        else {
+            "loudnorm=print_format=json"
+        };
+
+        let step = format!("measuring the loudness of audio track {}", idx);
+        let mut command = env.ffmpeg();
+        command
             .arg("-hide_banner")
             // read this specific file
             .arg("-i")
-            .arg(&path)
+            .arg(&inputs[track.source.file].path)
             // ignore the video portion
             .arg("-vn")
             .arg("-map")
-            .arg(&format!("0:a:{}", unbounded_channel_no))
+            .arg(format!("0:a:{}", track.source.stream))
             // use the filter loudnorm to print the loudness constants in JSON
             .arg("-filter:a")
-            .arg("loudnorm=print_format=json")
+            .arg(filter_graph)
             // we're not writing anything so pipe the output into /dev/null with
             // null type
             .arg("-f")
             .arg("null")
-            .arg("/dev/null")
-            .kill_on_drop(true)
-            .output()
-            .await
-            .unwrap();
+            .arg("/dev/null");
+        let audio_stats = run_ffmpeg(&mut command, &step).await?;
 
         let mut line_ring = VecDeque::with_capacity(12);
         let stderr = String::from_utf8(audio_stats.stderr).unwrap();
@@ -152,89 +758,224 @@ async fn determine_audio_constants(
             object_string += line;
         }
 
-        if let Ok(consts) = serde_json::from_str(&object_string) {
-            constants.push(consts);
-        }
-        else {
-            break;
+        match serde_json::from_str(&object_string) {
+            Ok(consts) => constants.push(consts),
+            Err(_) => {
+                return Err(JobError::new(
+                    step,
+                    "loudnorm did not print the measured loudness".to_owned(),
+                ))
+            },
         }
     }
 
-    constants
+    Ok(constants)
 }
 
-/// Use FFmpeg to convert audio tracks into Opus, given a target I.
+/// An audio track that has been converted and is ready to be merged.
+#[derive(Debug, Clone)]
+struct ConvertedAudioTrack {
+    path: PathBuf,
+    default: bool,
+    language: Option<String>,
+    title: Option<String>,
+}
+
+/// Use FFmpeg to convert the planned audio tracks into Opus, given the audio
+/// settings.
 ///
-/// TODO: Return Vec<PathBuf> instead, each PathBuf a path to a converted
-/// audio channel.
+/// Each track is handled according to its decision: normalized with loudnorm,
+/// only transcoded into Opus, or copied as-is. Tracks with a gain are tagged
+/// with it.
 async fn convert_audio_tracks(
+    tracks: &[AudioTrackPlan],
     constants: &[AudioConstants],
-    input_path: impl AsRef<OsStr>,
-    target_i: f64,
-) -> PathBuf {
-    let mut converted_audio_paths = vec![];
-
-    for (idx, constant) in constants.iter().enumerate() {
-        let filter_graph = format!(
-            "loudnorm=linear=true:i={}:measured_I={}:measured_LRA={}:\
-             measured_tp={}:measured_thresh={}",
-            target_i,
-            constant.input_i,
-            constant.input_lra,
-            constant.input_tp,
-            constant.input_thresh,
+    decisions: &[AudioTrackDecision],
+    gains: &[Option<AudioGain>],
+    inputs: &[ProbedInput],
+    settings: &AudioSettings,
+    env: &JobEnvironment,
+) -> Result<Vec<ConvertedAudioTrack>, JobError> {
+    let mut converted_audio_tracks = vec![];
+
+    for (idx, ((track, constant), decision)) in tracks
+        .iter()
+        .zip(constants.iter())
+        .zip(decisions.iter())
+        .enumerate()
+    {
+        let path = env.path(format!("audio_{}.opus", idx));
+        let opus = &track.opus;
+        let layout = OpusLayout::for_stream(
+            stream_info(inputs, track.source),
+            opus.is_downmixed(),
         );
 
-        let path = format!("./audio_{}.opus", idx);
+        // the offset goes first, followed by the layout conversion since the
+        // track has been measured after downmixing
+        let mut filters = vec![];
+        filters.extend(track.offset_filter());
+        filters.extend(layout.filter.clone());
 
-        let command = Command::new("ffmpeg")
+        let mut command = env.ffmpeg();
+        command
             .arg("-hide_banner")
             .arg("-y")
             .arg("-i")
-            .arg(&input_path)
+            .arg(&inputs[track.source.file].path)
             .arg("-vn")
             .arg("-map")
-            .arg(&format!("0:a:{}", idx))
-            // use the filter loudnorm to print the loudness constants in JSON
-            .arg("-filter:a")
-            .arg(&filter_graph)
-            // we're not writing anything so pipe the output into /dev/null with
-            // null type
-            .arg("-codec:a")
-            .arg("libopus")
-            .arg("-compression_level")
-            .arg("10")
-            .arg(&path)
-            .kill_on_drop(true)
-            .output()
-            .await
-            .unwrap();
+            .arg(format!("0:a:{}", track.source.stream));
+
+        match decision {
+            AudioTrackDecision::Normalize => {
+                let loudnorm = format!(
+                    "loudnorm=linear=true:i={}:tp={}:measured_I={}:\
+                     measured_LRA={}:measured_tp={}:measured_thresh={}",
+                    settings.target_i,
+                    settings.target_tp,
+                    constant.input_i,
+                    constant.input_lra,
+                    constant.input_tp,
+                    constant.input_thresh,
+                );
+                filters.push(loudnorm);
+
+                command.arg("-filter:a").arg(filters.join(","));
+                add_opus_args(&mut command, opus, &layout);
+            },
+
+            AudioTrackDecision::Transcode => {
+                if !filters.is_empty() {
+                    command.arg("-filter:a").arg(filters.join(","));
+                }
+
+                add_opus_args(&mut command, opus, &layout);
+            },
+
+            AudioTrackDecision::StreamCopy => {
+                command.arg("-codec:a").arg("copy");
+            },
+        }
+
+        if let Some(gain) = gains.get(idx).copied().flatten() {
+            for (key, value) in gain.tags(settings.loudness_mode) {
+                command
+                    .arg("-metadata:s:a:0")
+                    .arg(format!("{}={}", key, value));
+            }
+        }
+
+        command.arg(&path);
+        run_ffmpeg(&mut command, &format!("converting audio track {}", idx))
+            .await?;
+
+        converted_audio_tracks.push(ConvertedAudioTrack {
+            path,
+            default: track.default,
+            language: track.language.clone(),
+            title: track.title.clone(),
+        });
+    }
+
+    Ok(converted_audio_tracks)
+}
+
+/// Add the arguments for encoding a track into Opus.
+fn add_opus_args(
+    command: &mut Command,
+    opus: &OpusSettings,
+    layout: &OpusLayout,
+) {
+    command
+        .arg("-codec:a")
+        .arg("libopus")
+        .arg("-compression_level")
+        .arg("10")
+        .arg("-mapping_family")
+        .arg(layout.mapping_family.to_string());
+
+    if let Some(bitrate) = opus.bitrate {
+        command.arg("-b:a").arg(bitrate.to_string());
+    }
 
-        converted_audio_paths.push(path.into());
+    if let Some(vbr) = opus.vbr {
+        command.arg("-vbr").arg(vbr.as_arg());
     }
 
-    // TODO: actually return a list of audio files
-    //converted_audio_paths
-    converted_audio_paths.into_iter().next().unwrap()
+    if let Some(application) = opus.application {
+        command.arg("-application").arg(application.as_arg());
+    }
 }
 
-// TODO: Return Vec<PathBuf> instead, each PathBuf a path to a converted
-// audio channel.
+/// Convert the audio tracks of the output layout.
+///
+/// Returns `None` if the layout has no audio tracks, in which case the output
+/// will not have any audio.
 async fn convert_audio(
-    path: impl AsRef<OsStr>,
+    inputs: &[ProbedInput],
+    layout: &OutputLayout,
+    settings: &AudioSettings,
+    env: &JobEnvironment,
     sender: UnboundedSender<JobToOverseerMessage>,
-) -> PathBuf {
-    let audio_constants = determine_audio_constants(&path).await;
+) -> Result<Option<Vec<ConvertedAudioTrack>>, JobError> {
+    if layout.audio.is_empty() {
+        drop(sender.send(JobToOverseerMessage::NoAudioStream));
+        return Ok(None);
+    }
+
+    let audio_tracks: Arc<[AudioTrackPlan]> =
+        Arc::from(AudioTrackPlan::plan(layout, inputs, settings));
+    drop(sender.send(JobToOverseerMessage::AudioTracksPlanned(
+        audio_tracks.clone(),
+    )));
+
+    let audio_constants =
+        determine_audio_constants(inputs, &audio_tracks, env).await?;
     let audio_constants: Arc<[AudioConstants]> = Arc::from(audio_constants);
     drop(sender.send(JobToOverseerMessage::AudioConstantsDetermined(
         audio_constants.clone(),
     )));
 
-    let converted_audios =
-        convert_audio_tracks(&*audio_constants, &path, -18.).await;
+    let audio_decisions = audio_tracks
+        .iter()
+        .zip(audio_constants.iter())
+        .map(|(track, constants)| {
+            AudioTrackDecision::decide(
+                constants,
+                stream_info(inputs, track.source),
+                track,
+                settings,
+            )
+        })
+        .collect::<Arc<[AudioTrackDecision]>>();
+    drop(sender.send(JobToOverseerMessage::AudioDecisionsDetermined(
+        audio_decisions.clone(),
+    )));
+
+    let audio_gains = audio_constants
+        .iter()
+        .map(|constants| AudioGain::compute(constants, settings))
+        .collect::<Arc<[Option<AudioGain>]>>();
+    if settings.loudness_mode != LoudnessMode::Normalize {
+        drop(sender.send(JobToOverseerMessage::AudioGainsDetermined(
+            audio_gains.clone(),
+        )));
+    }
+
+    let converted_audios = convert_audio_tracks(
+        &audio_tracks,
+        &audio_constants,
+        &audio_decisions,
+        &audio_gains,
+        inputs,
+        settings,
+        env,
+    )
+    .await?;
     drop(sender.send(JobToOverseerMessage::AudioSecondPassFinished));
 
-    converted_audios
+    Ok(Some(converted_audios))
 }
 
 //////// Video Section /////////////////////////////////////////////////////////
@@ -256,176 +997,2479 @@ fn crf(
         .max(0) as usize
 }
 
-async fn determine_video_dimensions(
-    path: impl AsRef<OsStr>
-) -> Option<(usize, usize)> {
-    let command = Command::new("ffprobe")
+/// The mean frame difference, out of 255, of a video of typical complexity.
+/// Videos at this complexity keep the CRF from their resolution.
+const REFERENCE_COMPLEXITY: f64 = 4.;
+
+/// Adjust the CRF from the resolution given the complexity of the video, up
+/// to `band` away from it.
+///
+/// Each doubling of the complexity lowers the CRF by 2 to keep the details
+/// of busy videos, and each halving raises it by 2 since static videos hide
+/// the loss.
+fn adjust_crf(
+    base: usize,
+    complexity: f64,
+    band: usize,
+) -> usize {
+    let band = band as f64;
+    let offset = (-2. * (complexity.max(0.01) / REFERENCE_COMPLEXITY).log2())
+        .round()
+        .clamp(-band, band);
+
+    (base as f64 + offset).clamp(0., 63.) as usize
+}
+
+/// Use FFmpeg to measure the temporal complexity of a video, as the mean
+/// difference between consecutive frames, out of 255.
+///
+/// The video is measured at a small size since only the motion matters.
+async fn measure_complexity(
+    path: impl AsRef<OsStr>,
+    env: &JobEnvironment,
+) -> Option<f64> {
+    let output = env
+        .ffmpeg()
         .arg("-hide_banner")
-        .arg("-v")
-        .arg("error")
-        .arg("-select_streams")
-        .arg("v")
-        .arg("-show_entries")
-        .arg("stream=width,height")
-        .arg("-print_format")
-        .arg("json")
+        .arg("-i")
         .arg(path)
+        .arg("-an")
+        .arg("-sn")
+        .arg("-filter:v")
+        .arg(
+            "scale=320:-2,tblend=all_mode=difference,signalstats,\
+             metadata=mode=print:key=lavfi.signalstats.YAVG:file=-",
+        )
+        .arg("-f")
+        .arg("null")
+        .arg("/dev/null")
+        .kill_on_drop(true)
         .output()
         .await
-        .unwrap();
+        .ok()?;
 
-    #[derive(Deserialize)]
-    struct Dimensions {
-        width: usize,
-        height: usize,
+    // the CRF falls back to the one from the resolution
+    if !output.status.success() {
+        return None;
     }
 
-    #[derive(Deserialize)]
-    struct Entries {
-        // programs:
-        streams: Vec<Dimensions>,
-    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let differences = stdout
+        .lines()
+        .filter_map(|l| l.strip_prefix("lavfi.signalstats.YAVG="))
+        .filter_map(|v| v.trim().parse::<f64>().ok())
+        .collect::<Vec<_>>();
 
-    let dimensions = String::from_utf8(command.stdout).unwrap();
-    serde_json::from_str::<Entries>(&dimensions)
-        .ok()
-        .and_then(|e| e.streams.into_iter().next())
-        .map(|dim| (dim.width, dim.height))
+    if differences.is_empty() {
+        None
+    }
+    else {
+        Some(differences.iter().sum::<f64>() / differences.len() as f64)
+    }
 }
 
-async fn convert_video(
-    path: impl AsRef<OsStr>,
-    sender: UnboundedSender<JobToOverseerMessage>,
-) -> PathBuf {
-    let crf_determine_future = async {
-        let (width, height) = determine_video_dimensions(&path).await.unwrap();
-        drop(sender.send(JobToOverseerMessage::VideoDimensionsDetermined(
-            width, height,
-        )));
+/// The lowest and highest CRFs that the search for the VMAF target tries.
+const VMAF_CRF_RANGE: (usize, usize) = (10, 55);
+/// How many samples are taken from a video to be scored.
+const VMAF_SAMPLE_COUNT: usize = 3;
+/// How long each sample is, in seconds.
+const VMAF_SAMPLE_LENGTH: f64 = 4.;
+
+/// A segment of the video that is encoded and scored in the VMAF mode.
+#[derive(Debug, Copy, Clone, Serialize)]
+pub struct VmafSample {
+    /// Where the sample starts, in seconds.
+    pub start: f64,
+    /// How long the sample is, in seconds.
+    pub length: f64,
+}
 
-        let video_crf = crf(width, height);
-        drop(sender.send(JobToOverseerMessage::VideoCrfDetermined(video_crf)));
+impl VmafSample {
+    /// Pick the samples of a video, spread evenly across it.
+    ///
+    /// Videos too short for separate samples, or of unknown duration, are
+    /// sampled once from the start.
+    fn pick(duration: Option<f64>) -> Vec<VmafSample> {
+        let duration = match duration {
+            Some(d) if d > VMAF_SAMPLE_COUNT as f64 * VMAF_SAMPLE_LENGTH => d,
+            Some(d) if d > 0. => {
+                return vec![VmafSample {
+                    start: 0.,
+                    length: d,
+                }]
+            },
+            _ => {
+                return vec![VmafSample {
+                    start: 0.,
+                    length: VMAF_SAMPLE_LENGTH,
+                }]
+            },
+        };
+
+        (1 ..= VMAF_SAMPLE_COUNT)
+            .map(|i| {
+                let center =
+                    duration * i as f64 / (VMAF_SAMPLE_COUNT + 1) as f64;
+                VmafSample {
+                    start: center - VMAF_SAMPLE_LENGTH / 2.,
+                    length: VMAF_SAMPLE_LENGTH,
+                }
+            })
+            .collect()
+    }
+}
 
-        video_crf
-    };
+/// The scores of the samples encoded at one CRF.
+#[derive(Debug, Clone, Serialize)]
+pub struct VmafTrial {
+    pub crf: usize,
+    /// The score of each sample, in the same order as the samples. The trial
+    /// stops at the first sample that could not be scored, which is `None`.
+    pub scores: Vec<Option<f64>>,
+    /// The mean of the scores, if every sample has been scored.
+    pub mean: Option<f64>,
+}
 
-    // TODO: temporary name
-    let first_pass_log = "./ffmpeg2pass-0.log";
+/// A sample of the video as it is encoded for a VMAF trial.
+struct VmafSampleEncode<'a> {
+    sample: VmafSample,
+    /// The index of the sample, which its files are named after.
+    idx: usize,
+    /// The filter that the video is encoded with, if any.
+    filter: Option<&'a str>,
+    /// The dimensions of the output, which the source is scaled to.
+    dimensions: (usize, usize),
+}
 
-    let first_pass_future = async {
-        Command::new("ffmpeg")
-            .arg("-hide_banner")
-            .arg("-i")
-            .arg(&path)
-            .arg("-codec:v")
-            .arg("libaom-av1")
-            .arg("-an")
-            .arg("-pass")
-            .arg("1")
-            .arg("-passlogfile")
-            .arg(&first_pass_log)
-            .arg("-f")
-            .arg("null")
-            .arg("/dev/null")
-            .output()
-            .await
-            .unwrap();
-        drop(sender.send(JobToOverseerMessage::VideoFirstPassFinished));
-    };
+/// Encode a sample of the video at the CRF and score it against the source
+/// with FFmpeg's libvmaf filter.
+///
+/// The source is scaled to the output dimensions so that both are compared at
+/// the same size.
+async fn score_sample(
+    path: impl AsRef<OsStr>,
+    sample_encode: &VmafSampleEncode<'_>,
+    crf: usize,
+    encode: &VideoEncode,
+    env: &JobEnvironment,
+) -> Option<f64> {
+    let VmafSampleEncode {
+        sample,
+        idx,
+        filter,
+        dimensions,
+    } = *sample_encode;
+    let settings = &encode.settings;
+    let encoded = env.path(format!(
+        "{}-vmaf-{}-{}.{}",
+        encode.name,
+        crf,
+        idx,
+        settings.codec.extension()
+    ));
+
+    let mut command = env.ffmpeg();
+    command
+        .arg("-hide_banner")
+        .arg("-y")
+        .arg("-ss")
+        .arg(sample.start.to_string())
+        .arg("-t")
+        .arg(sample.length.to_string())
+        .arg("-i")
+        .arg(&path);
+    settings.add_encoder_args(&mut command, filter, None);
 
-    let (crf, _) = join!(crf_determine_future, first_pass_future,);
+    let status = command
+        .arg("-crf")
+        .arg(crf.to_string())
+        .arg("-an")
+        .arg("-sn")
+        .arg(&encoded)
+        .kill_on_drop(true)
+        .status()
+        .await
+        .ok()?;
+    if !status.success() {
+        drop(tokio::fs::remove_file(&encoded).await);
+        return None;
+    }
 
-    // TODO: add message here that conversion video conversion has started
-    // and send the supposed log file
-    Command::new("ffmpeg")
+    let output = env
+        .ffmpeg()
         .arg("-hide_banner")
         .arg("-i")
+        .arg(&encoded)
+        .arg("-ss")
+        .arg(sample.start.to_string())
+        .arg("-t")
+        .arg(sample.length.to_string())
+        .arg("-i")
         .arg(&path)
-        // General video options
-        .arg("-codec:v")
-        .arg("libaom-av1")
-        .arg("-crf")
-        .arg(&format!("{}", crf))
-        .arg("-pass")
-        .arg("2")
-        .arg("-threads")
-        .arg("1")
-        .arg("-cpu-used")
-        .arg("0")
-        // AOM-AV1 specific flags start
-        .arg("-auto-alt-ref")
-        .arg("1")
-        .arg("-arnr-max-frames")
-        .arg("7")
-        .arg("-arnr-strength")
-        .arg("4")
-        .arg("-tune")
-        .arg("0")
-        .arg("-lag-in-frames")
-        .arg("35")
-        .arg("-tile-columns")
-        .arg("0")
-        .arg("-row-mt")
-        .arg("1")
-        .arg("output.webm")
-        // AOM-AV1 specific flags end
+        .arg("-filter_complex")
+        .arg(format!(
+            "[0:v]setpts=PTS-STARTPTS[distorted];\
+             [1:v]{},setpts=PTS-STARTPTS[reference];\
+             [distorted][reference]libvmaf",
+            settings.scale_filter(dimensions)
+        ))
+        .arg("-f")
+        .arg("null")
+        .arg("-")
+        .kill_on_drop(true)
         .output()
-        .await
-        .unwrap();
-    drop(sender.send(JobToOverseerMessage::VideoSecondPassFinished));
-
-    "output.webm".into()
+        .await;
+    drop(tokio::fs::remove_file(&encoded).await);
+    let output = output.ok()?;
+
+    // libvmaf writes the pooled score as `VMAF score: 93.456789`
+    String::from_utf8_lossy(&output.stderr)
+        .lines()
+        .filter_map(|l| l.split("VMAF score:").nth(1))
+        .filter_map(|v| v.trim().parse::<f64>().ok())
+        .next_back()
 }
 
-//////// Common Area ///////////////////////////////////////////////////////////
+/// Search for the highest CRF whose samples reach the VMAF target on average.
+///
+/// The score falls as the CRF rises, so the range is halved at every trial.
+/// If even the lowest CRF of the range misses the target, it is used anyway.
+/// The range is capped at the highest CRF of the encoder.
+///
+/// Returns `None` as soon as a sample cannot be scored, since the mean of the
+/// rest says nothing about the target.
+async fn search_vmaf_crf(
+    path: impl AsRef<OsStr>,
+    samples: &[VmafSample],
+    filter: Option<&str>,
+    dimensions: (usize, usize),
+    encode: &VideoEncode,
+    env: &JobEnvironment,
+    sender: &UnboundedSender<JobToOverseerMessage>,
+) -> Option<usize> {
+    let (mut low, high) = VMAF_CRF_RANGE;
+    let mut high = high.min(encode.settings.codec.max_crf());
+    let mut chosen = low;
+
+    while low <= high {
+        let crf = (low + high) / 2;
+
+        let mut scores = Vec::with_capacity(samples.len());
+        for (idx, sample) in samples.iter().enumerate() {
+            let sample_encode = VmafSampleEncode {
+                sample: *sample,
+                idx,
+                filter,
+                dimensions,
+            };
+            let score =
+                score_sample(&path, &sample_encode, crf, encode, env).await;
+            scores.push(score);
+
+            if score.is_none() {
+                break;
+            }
+        }
+        let mean = scores
+            .iter()
+            .copied()
+            .sum::<Option<f64>>()
+            .map(|sum| sum / scores.len().max(1) as f64);
+
+        encode.report(
+            sender,
+            JobToOverseerMessage::VideoVmafTrialFinished(VmafTrial {
+                crf,
+                scores,
+                mean,
+            }),
+        );
 
-async fn merge_media(
-    audio: PathBuf,
-    video: PathBuf,
-    sender: UnboundedSender<JobToOverseerMessage>,
-) -> PathBuf {
-    let output = "output.webm".into();
+        let mean = mean?;
+        if mean >= encode.settings.vmaf_target {
+            chosen = crf;
+            low = crf + 1;
+        }
+        else if crf == 0 {
+            break;
+        }
+        else {
+            high = crf - 1;
+        }
+    }
 
-    let command = Command::new("ffmpeg")
-        .arg("-hide_banner")
-        .arg("-i")
-        .arg(&audio)
-        .arg("-i")
-        .arg(&video)
-        .arg("-c")
-        .arg("copy")
-        .arg(&output);
-    sender.send(JobToOverseerMessage::VideoConversionFinished);
+    Some(chosen)
+}
+
+/// How the CRF of the video is chosen.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CrfMode {
+    /// Only from the resolution of the output.
+    #[default]
+    Resolution,
+    /// From the resolution, then adjusted by the complexity of the video.
+    Content,
+    /// The highest CRF whose samples of the video still reach the VMAF
+    /// target.
+    Vmaf,
+}
 
-    output
+/// The CRF chosen for the video and how it has been chosen.
+#[derive(Debug, Copy, Clone, Serialize)]
+pub struct CrfChoice {
+    /// The CRF from the resolution of the output.
+    pub base: usize,
+    /// The CRF that the video is encoded with.
+    pub adjusted: usize,
+    /// The measured complexity of the video, if it has been measured.
+    pub complexity: Option<f64>,
+    /// The VMAF target that the CRF has been searched for, if it has been.
+    pub vmaf_target: Option<f64>,
+    /// Whether the search has been given up on because a sample could not be
+    /// scored, in which case the CRF is the one from the resolution.
+    pub vmaf_failed: bool,
 }
 
+/// Settings on how the video of a job is converted.
 #[derive(Debug, Clone)]
-pub struct JobStatus {
-    audio: AudioVideoStatus,
-    video: AudioVideoStatus,
+pub struct VideoSettings {
+    pub codec: VideoCodec,
+    /// The speed preset of the encoder, with lower being slower but more
+    /// efficient. If `None`, the slowest preset is used.
+    pub cpu_used: Option<u8>,
+    /// The maximum height of the output. Taller videos are scaled down,
+    /// keeping their aspect ratio.
+    pub max_height: Option<usize>,
+    /// The maximum width of the output. Wider videos are scaled down, keeping
+    /// their aspect ratio.
+    pub max_width: Option<usize>,
+    /// The height that the video is scaled to, up or down, before the maximum
+    /// width and height are applied.
+    pub target_height: Option<usize>,
+    pub scaler: Scaler,
+    /// Whether to drop the video and only output the audio.
+    pub disabled: bool,
+    pub crf_mode: CrfMode,
+    /// How far the CRF can be adjusted from the one from the resolution.
+    pub crf_band: usize,
+    /// The mean VMAF score that the samples have to reach in the VMAF mode.
+    pub vmaf_target: f64,
+    /// The size, in bytes, that the output has to fit in. If set, the video
+    /// is encoded at the bitrate that fits it rather than at a CRF.
+    pub target_size: Option<usize>,
+    /// The maximum bitrate, in bits per second, that players have to be able
+    /// to keep up with.
+    pub max_bitrate: Option<u64>,
+    /// The size of the buffer that the maximum bitrate is kept over, in bits.
+    /// Defaults to two seconds at the maximum bitrate, and is only given
+    /// along with it. Only x264 takes it.
+    pub buffer_size: Option<u64>,
+    /// The maximum time between keyframes, in seconds.
+    pub keyframe_interval: Option<f64>,
+    /// Whether the encoder can place more keyframes at scene cuts. Without
+    /// it, keyframes are only placed at the keyframe interval, which has to
+    /// be given for this to have any effect.
+    pub scene_cut: bool,
+    /// The heights of the renditions to encode for adaptive streaming. If
+    /// empty, a single video is encoded.
+    pub ladder: Vec<usize>,
+    /// The segments that the renditions of the ladder are packaged into.
+    pub segments: SegmentFormat,
+    /// The container of the output. If `None`, it is WebM if WebM allows the
+    /// codec, otherwise MP4.
+    pub container: Option<Container>,
+    /// The text subtitle stream that is burned into the picture, for players
+    /// that cannot show subtitle tracks.
+    pub burn_subtitles: Option<SubtitleSource>,
+}
 
-    audio_constants: Option<Arc<[AudioConstants]>>,
-    dimensions: Option<(usize, usize)>,
-    crf: Option<usize>,
+impl Default for VideoSettings {
+    fn default() -> VideoSettings {
+        VideoSettings {
+            codec: VideoCodec::default(),
+            cpu_used: None,
+            max_height: None,
+            max_width: None,
+            target_height: None,
+            scaler: Scaler::default(),
+            disabled: false,
+            crf_mode: CrfMode::default(),
+            crf_band: 6,
+            vmaf_target: 93.,
+            target_size: None,
+            max_bitrate: None,
+            buffer_size: None,
+            keyframe_interval: None,
+            scene_cut: true,
+            ladder: vec![],
+            segments: SegmentFormat::default(),
+            container: None,
+            burn_subtitles: None,
+        }
+    }
+}
 
-    video_conversion_log_path: Option<PathBuf>,
+/// The scaling algorithms of FFmpeg that the video can be scaled with.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scaler {
+    Bilinear,
+    #[default]
+    Bicubic,
+    Lanczos,
+    Spline,
+    Area,
+}
+
+impl Scaler {
+    fn as_flag(self) -> &'static str {
+        match self {
+            Scaler::Bilinear => "bilinear",
+            Scaler::Bicubic => "bicubic",
+            Scaler::Lanczos => "lanczos",
+            Scaler::Spline => "spline",
+            Scaler::Area => "area",
+        }
+    }
+}
+
+/// The video encoders that the converter can use.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VideoCodec {
+    #[default]
+    Av1,
+    Vp9,
+    /// For players that cannot decode either of the others.
+    H264,
+}
+
+/// The presets of x264 from the slowest to the fastest, picked by the CPU
+/// used like the speed presets of the other encoders.
+const X264_PRESETS: [&str; 9] = [
+    "veryslow",
+    "slower",
+    "slow",
+    "medium",
+    "fast",
+    "faster",
+    "veryfast",
+    "superfast",
+    "ultrafast",
+];
+
+impl VideoCodec {
+    /// The name of the FFmpeg encoder of the codec.
+    pub fn encoder(self) -> &'static str {
+        match self {
+            VideoCodec::Av1 => "libaom-av1",
+            VideoCodec::Vp9 => "libvpx-vp9",
+            VideoCodec::H264 => "libx264",
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            VideoCodec::Av1 => "AV1",
+            VideoCodec::Vp9 => "VP9",
+            VideoCodec::H264 => "H.264",
+        }
+    }
+
+    /// The extension of the files that the video is encoded into, before it
+    /// is muxed.
+    fn extension(self) -> &'static str {
+        match self {
+            VideoCodec::Av1 | VideoCodec::Vp9 => "webm",
+            VideoCodec::H264 => "mp4",
+        }
+    }
+
+    /// The name of the FFmpeg muxer of the output.
+    pub fn muxer(self) -> &'static str {
+        // the extensions happen to be the names of the muxers
+        self.extension()
+    }
+
+    /// The highest CRF of the encoder.
+    fn max_crf(self) -> usize {
+        match self {
+            VideoCodec::Av1 | VideoCodec::Vp9 => 63,
+            VideoCodec::H264 => 51,
+        }
+    }
+
+    /// Scale a CRF of the 0 to 63 range of libvpx and libaom into the range of
+    /// the encoder.
+    fn scale_crf(
+        self,
+        crf: usize,
+    ) -> usize {
+        (crf as f64 * self.max_crf() as f64 / 63.).round() as usize
+    }
+
+    /// Whether the encoder makes use of a first pass when encoding at a CRF.
+    /// x264 only takes two passes to hit a bitrate.
+    fn two_pass_crf(self) -> bool {
+        match self {
+            VideoCodec::Av1 | VideoCodec::Vp9 => true,
+            VideoCodec::H264 => false,
+        }
+    }
+}
+
+/// The containers that an output with video can be muxed into.
+///
+/// Every output has Opus audio, which all of them allow.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Container {
+    WebM,
+    #[serde(alias = "mkv")]
+    Matroska,
+    Mp4,
+}
+
+impl Container {
+    fn name(self) -> &'static str {
+        match self {
+            Container::WebM => "WebM",
+            Container::Matroska => "Matroska",
+            Container::Mp4 => "MP4",
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            Container::WebM => "webm",
+            Container::Matroska => "mkv",
+            Container::Mp4 => "mp4",
+        }
+    }
+
+    /// The name of the FFmpeg muxer of the container.
+    pub fn muxer(self) -> &'static str {
+        match self {
+            Container::WebM => "webm",
+            Container::Matroska => "matroska",
+            Container::Mp4 => "mp4",
+        }
+    }
+
+    /// The FFmpeg encoder of the only text subtitle format that the container
+    /// can carry, if it is limited to one.
+    pub fn text_subtitle_encoder(self) -> Option<&'static str> {
+        match self {
+            Container::WebM => Some("webvtt"),
+            Container::Matroska => None,
+            Container::Mp4 => Some("mov_text"),
+        }
+    }
+
+    /// What is done with a subtitle stream in an output in the container.
+    ///
+    /// Matroska carries every subtitle format as-is. The others only carry
+    /// their own text format, which the other text formats are converted
+    /// into, while image subtitles are dropped.
+    fn subtitle_decision(
+        self,
+        stream: &SubtitleStreamInfo,
+    ) -> SubtitleDecision {
+        match self.text_subtitle_encoder() {
+            None => SubtitleDecision::Copy,
+            Some(_) if stream.is_image() => SubtitleDecision::Drop,
+            // the encoders happen to be named after their codecs
+            Some(encoder) if stream.codec_name == encoder => {
+                SubtitleDecision::Copy
+            },
+            Some(encoder) => SubtitleDecision::Convert(encoder),
+        }
+    }
+
+    /// Whether the container can carry video in the codec. WebM only allows
+    /// the royalty-free codecs.
+    fn allows(
+        self,
+        codec: VideoCodec,
+    ) -> bool {
+        match self {
+            Container::WebM => codec != VideoCodec::H264,
+            Container::Matroska | Container::Mp4 => true,
+        }
+    }
+}
+
+/// A video codec that a container cannot carry.
+#[derive(Debug, Clone)]
+pub struct ContainerError {
+    codec: VideoCodec,
+    container: Container,
+}
+
+impl ContainerError {
+    pub fn write_error_msg<W>(
+        &self,
+        writer: &mut W,
+    ) -> Result<(), std::fmt::Error>
+    where
+        W: Write,
+    {
+        write!(
+            writer,
+            "{} video cannot be muxed into {}",
+            self.codec.name(),
+            self.container.name()
+        )
+    }
+
+    pub fn as_error_msg(&self) -> String {
+        let mut retval = String::new();
+        self.write_error_msg(&mut retval).unwrap();
+        retval
+    }
+}
+
+/// A subtitle stream that cannot be burned into the video.
+#[derive(Debug, Clone)]
+pub enum BurnInError {
+    /// The source refers to a file that has not been uploaded.
+    NoSuchFile { file: usize, file_count: usize },
+    /// The source refers to a subtitle stream the file does not have.
+    NoSuchStream {
+        source: SubtitleSource,
+        stream_count: usize,
+    },
+    /// The subtitles are images, which the `subtitles` filter cannot render.
+    ImageSubtitles {
+        source: SubtitleSource,
+        codec: String,
+    },
+}
+
+impl BurnInError {
+    pub fn write_error_msg<W>(
+        &self,
+        writer: &mut W,
+    ) -> Result<(), std::fmt::Error>
+    where
+        W: Write,
+    {
+        use BurnInError::*;
+
+        match self {
+            NoSuchFile { file, file_count } => {
+                write!(
+                    writer,
+                    "Subtitles to burn in are taken from file #{}, but only {} \
+                     file(s) have been uploaded (#0 to #{})",
+                    file,
+                    file_count,
+                    file_count.saturating_sub(1)
+                )
+            },
+            NoSuchStream {
+                source,
+                stream_count,
+            } => {
+                write!(
+                    writer,
+                    "Subtitles to burn in are taken from subtitle stream {} of \
+                     file #{}, but the file only has {} subtitle stream(s)",
+                    source.stream, source.file, stream_count
+                )
+            },
+            ImageSubtitles { source, codec } => {
+                write!(
+                    writer,
+                    "Subtitle stream {} of file #{} is made of images \
+                     ({}), only text subtitles can be burned in",
+                    source.stream, source.file, codec
+                )
+            },
+        }
+    }
+
+    pub fn as_error_msg(&self) -> String {
+        let mut retval = String::new();
+        self.write_error_msg(&mut retval).unwrap();
+        retval
+    }
+}
+
+impl VideoSettings {
+    /// The container that the output is muxed into.
+    pub fn output_container(&self) -> Container {
+        match self.container {
+            Some(container) => container,
+            None if Container::WebM.allows(self.codec) => Container::WebM,
+            None => Container::Mp4,
+        }
+    }
+
+    /// Check that the codec can be muxed into the container of the output,
+    /// or into the segments if there is a ladder.
+    pub fn check_container(&self) -> Result<(), ContainerError> {
+        let container = if self.ladder.is_empty() {
+            self.output_container()
+        }
+        else {
+            self.segments.container()
+        };
+
+        if container.allows(self.codec) {
+            Ok(())
+        }
+        else {
+            Err(ContainerError {
+                codec: self.codec,
+                container,
+            })
+        }
+    }
+
+    /// Check that the subtitles to burn in, if any, are a text subtitle
+    /// stream of an uploaded file.
+    pub fn check_burn_in(
+        &self,
+        inputs: &[ProbedInput],
+    ) -> Result<(), BurnInError> {
+        let source = match self.burn_subtitles {
+            Some(source) => source,
+            None => return Ok(()),
+        };
+
+        let input = inputs.get(source.file).ok_or(BurnInError::NoSuchFile {
+            file: source.file,
+            file_count: inputs.len(),
+        })?;
+        let stream = input.subtitle_streams.get(source.stream).ok_or(
+            BurnInError::NoSuchStream {
+                source,
+                stream_count: input.subtitle_streams.len(),
+            },
+        )?;
+
+        if stream.is_image() {
+            return Err(BurnInError::ImageSubtitles {
+                source,
+                codec: stream.codec_name.clone(),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// The dimensions of the output given the dimensions of the input.
+    ///
+    /// The video is scaled to the target height first, then scaled down to
+    /// fit in the maximum width and height, always keeping its aspect ratio.
+    /// Both dimensions are then rounded to even numbers since the encoders
+    /// require them.
+    fn output_dimensions(
+        &self,
+        width: usize,
+        height: usize,
+    ) -> (usize, usize) {
+        let mut width = width.max(1) as f64;
+        let mut height = height.max(1) as f64;
+
+        if let Some(target_height) = self.target_height {
+            width *= target_height as f64 / height;
+            height = target_height as f64;
+        }
+
+        if let Some(max_height) = self.max_height.map(|h| h as f64) {
+            if height > max_height {
+                width *= max_height / height;
+                height = max_height;
+            }
+        }
+
+        if let Some(max_width) = self.max_width.map(|w| w as f64) {
+            if width > max_width {
+                height *= max_width / width;
+                width = max_width;
+            }
+        }
+
+        let even = |d: f64| ((d / 2.).round() as usize * 2).max(2);
+        (even(width), even(height))
+    }
+
+    /// The filter that scales the video into the dimensions.
+    fn scale_filter(
+        &self,
+        (width, height): (usize, usize),
+    ) -> String {
+        format!("scale={}:{}:flags={}", width, height, self.scaler.as_flag())
+    }
+
+    /// The filter graph that the video is encoded through, given the
+    /// dimensions of the input and of the output.
+    ///
+    /// Every sample of the video has to go through the same graph. The passes
+    /// also burn in the subtitles, which the samples leave out since they
+    /// are compared against the source without them.
+    fn video_filter(
+        &self,
+        source: (usize, usize),
+        output: (usize, usize),
+    ) -> Option<String> {
+        (source != output).then(|| self.scale_filter(output))
+    }
+
+    /// The filter graph that both passes of the video are encoded through,
+    /// which is the one of the samples with the subtitles burned in after
+    /// scaling, so that they are rendered at the output resolution.
+    fn pass_filter(
+        &self,
+        filter: Option<&str>,
+        burn_in: Option<&BurnIn>,
+    ) -> Option<String> {
+        let burn_in = burn_in.map(BurnIn::filter);
+        match (filter, burn_in) {
+            (Some(filter), Some(burn_in)) => {
+                Some(format!("{},{}", filter, burn_in))
+            },
+            (filter, burn_in) => burn_in.or(filter.map(str::to_owned)),
+        }
+    }
+
+    /// Add the arguments for encoding with the chosen encoder, other than
+    /// the pass and the CRF.
+    ///
+    /// With a bitrate, the video is encoded as a constrained VBR at it, which
+    /// is not let to overshoot. Otherwise, it is encoded at a constant
+    /// quality, capped at the maximum bitrate if there is one.
+    ///
+    /// Only x264 takes the maximum bitrate and the buffer size as a VBV.
+    /// libvpx and libaom have none: FFmpeg turns `-maxrate` into the share
+    /// of the bitrate that a section of a two-pass encode can take, and
+    /// `-bufsize` into milliseconds at `-b:v`. They are kept under the cap by
+    /// encoding at it as `-b:v` without letting them overshoot.
+    fn add_encoder_args(
+        &self,
+        command: &mut Command,
+        filter: Option<&str>,
+        bitrate: Option<u64>,
+    ) {
+        let cpu_used = self.cpu_used.unwrap_or(0).to_string();
+
+        command.arg("-codec:v").arg(self.codec.encoder());
+
+        if let Some(filter) = filter {
+            command.arg("-filter:v").arg(filter);
+        }
+
+        match self.codec {
+            VideoCodec::Av1 => {
+                command
+                    .arg("-threads")
+                    .arg("1")
+                    .arg("-cpu-used")
+                    .arg(cpu_used)
+                    // AOM-AV1 specific flags start
+                    .arg("-auto-alt-ref")
+                    .arg("1")
+                    .arg("-arnr-max-frames")
+                    .arg("7")
+                    .arg("-arnr-strength")
+                    .arg("4")
+                    .arg("-tune")
+                    .arg("0")
+                    .arg("-lag-in-frames")
+                    .arg("35")
+                    .arg("-tile-columns")
+                    .arg("0")
+                    .arg("-row-mt")
+                    .arg("1");
+                // AOM-AV1 specific flags end
+            },
+
+            VideoCodec::Vp9 => {
+                command
+                    .arg("-cpu-used")
+                    .arg(cpu_used)
+                    // VP9 specific flags start
+                    .arg("-deadline")
+                    .arg("good")
+                    .arg("-auto-alt-ref")
+                    .arg("1")
+                    .arg("-lag-in-frames")
+                    .arg("25")
+                    .arg("-row-mt")
+                    .arg("1");
+                // VP9 specific flags end
+            },
+
+            VideoCodec::H264 => {
+                let preset = X264_PRESETS[self.cpu_used.unwrap_or(0) as usize];
+
+                command
+                    .arg("-preset")
+                    .arg(preset)
+                    // x264 specific flags start
+                    .arg("-profile:v")
+                    .arg("high")
+                    .arg("-pix_fmt")
+                    .arg("yuv420p");
+                // x264 specific flags end
+            },
+        }
+
+        match (bitrate, self.max_bitrate, self.codec) {
+            (Some(bitrate), max_bitrate, codec) => {
+                let bitrate = max_bitrate.map_or(bitrate, |m| bitrate.min(m));
+                command.arg("-b:v").arg(bitrate.to_string());
+
+                // x264 has no such option, and keeps to the bitrate anyway
+                if codec != VideoCodec::H264 {
+                    command.arg("-overshoot-pct").arg("0");
+                }
+            },
+            // constrained quality mode of every encoder, where the bitrate is
+            // the cap of the CRF
+            (None, Some(max_bitrate), codec) => {
+                command.arg("-b:v").arg(max_bitrate.to_string());
+
+                if codec != VideoCodec::H264 {
+                    command.arg("-overshoot-pct").arg("0");
+                }
+            },
+            // constant quality mode of libvpx
+            (None, None, VideoCodec::Vp9) => {
+                command.arg("-b:v").arg("0");
+            },
+            (None, None, VideoCodec::Av1 | VideoCodec::H264) => {},
+        }
+
+        if let (Some(max_bitrate), VideoCodec::H264) =
+            (self.max_bitrate, self.codec)
+        {
+            let buffer_size = self.buffer_size.unwrap_or(max_bitrate * 2);
+            command
+                .arg("-maxrate")
+                .arg(max_bitrate.to_string())
+                .arg("-bufsize")
+                .arg(buffer_size.to_string());
+        }
+    }
+
+    /// Add the arguments that place the keyframes, given the frame rate of
+    /// the video if it is known.
+    ///
+    /// Keyframes are forced at every interval by time, so that they are
+    /// placed right even if the frame rate is unknown. Both encoders take the
+    /// interval in frames as their maximum, and only place keyframes at scene
+    /// cuts if their minimum is lower.
+    fn add_keyframe_args(
+        &self,
+        command: &mut Command,
+        frame_rate: Option<f64>,
+    ) {
+        let interval = match self.keyframe_interval {
+            Some(interval) => interval,
+            None => return,
+        };
+
+        command
+            .arg("-force_key_frames")
+            .arg(format!("expr:gte(t,n_forced*{})", interval));
+
+        if let Some(frame_rate) = frame_rate {
+            let frames = ((interval * frame_rate).round() as usize).max(1);
+            command.arg("-g").arg(frames.to_string());
+
+            if !self.scene_cut {
+                command.arg("-keyint_min").arg(frames.to_string());
+            }
+        }
+
+        // x264 caps its minimum at half of its maximum, so its scene cut
+        // detection has to be turned off instead
+        if !self.scene_cut && self.codec == VideoCodec::H264 {
+            command.arg("-sc_threshold").arg("0");
+        }
+    }
+}
+
+/// The share of the target size that is left for the overhead of the
+/// container.
+const CONTAINER_OVERHEAD: f64 = 0.02;
+/// The lowest video bitrate, in bits per second, that a target size is allowed
+/// to come down to.
+const MIN_VIDEO_BITRATE: f64 = 10_000.;
+
+/// Compute the video bitrate, in bits per second, that makes the output fit in
+/// the target size of the settings, if there is one.
+///
+/// The audio takes its expected bitrate out of the target first, and the rest
+/// is spread over the duration of the video.
+pub fn target_video_bitrate(
+    inputs: &[ProbedInput],
+    layout: &OutputLayout,
+    audio: &AudioSettings,
+    video_settings: &VideoSettings,
+) -> Result<Option<u64>, TargetSizeError> {
+    let (target_size, video) = match (video_settings.target_size, layout.video)
+    {
+        (Some(target_size), Some(video)) => (target_size, video),
+        _ => return Ok(None),
+    };
+
+    if !video_settings.ladder.is_empty() {
+        return Err(TargetSizeError::Ladder);
+    }
+
+    let duration = match inputs[video].duration {
+        Some(d) if d > 0. => d,
+        _ => return Err(TargetSizeError::UnknownDuration),
+    };
+
+    let audio_bitrate = AudioTrackPlan::plan(layout, inputs, audio)
+        .iter()
+        .map(|track| track.expected_bitrate(inputs, audio))
+        .sum::<u64>();
+
+    let total = target_size as f64 * 8. * (1. - CONTAINER_OVERHEAD) / duration;
+    let video_bitrate = total - audio_bitrate as f64;
+
+    if video_bitrate < MIN_VIDEO_BITRATE {
+        return Err(TargetSizeError::TooSmall {
+            target_size,
+            duration,
+            audio_bitrate,
+        });
+    }
+
+    Ok(Some(video_bitrate as u64))
+}
+
+#[derive(Debug, Clone)]
+pub enum TargetSizeError {
+    /// The duration of the video could not be read.
+    UnknownDuration,
+    /// A ladder has several renditions rather than one output to fit.
+    Ladder,
+    /// The audio alone takes up the target size, or nearly so.
+    TooSmall {
+        target_size: usize,
+        duration: f64,
+        audio_bitrate: u64,
+    },
+}
+
+impl TargetSizeError {
+    pub fn write_error_msg<W>(
+        &self,
+        writer: &mut W,
+    ) -> Result<(), std::fmt::Error>
+    where
+        W: Write,
+    {
+        match self {
+            TargetSizeError::UnknownDuration => {
+                write!(
+                    writer,
+                    "Unable to fit the output in the target size since the \
+                     duration of the video is unknown"
+                )
+            },
+            TargetSizeError::Ladder => {
+                write!(
+                    writer,
+                    "A target size cannot be given along with a ladder"
+                )
+            },
+            TargetSizeError::TooSmall {
+                target_size,
+                duration,
+                audio_bitrate,
+            } => {
+                write!(
+                    writer,
+                    "Unable to fit {} seconds of video in {} bytes with the \
+                     audio at {} bits per second",
+                    duration, target_size, audio_bitrate
+                )
+            },
+        }
+    }
+
+    pub fn as_error_msg(&self) -> String {
+        let mut retval = String::new();
+        self.write_error_msg(&mut retval).unwrap();
+        retval
+    }
+}
+
+/// One encode of the video, of which a job has one for each of its targets
+/// or, with a ladder, one for each rendition.
+struct VideoEncode {
+    /// What the files of the encode are named after.
+    name: String,
+    /// The index of the rendition in the ladder, if the encode is one.
+    rendition: Option<usize>,
+    /// The index of the extra target, if the encode is for one.
+    target: Option<usize>,
+    settings: VideoSettings,
+    /// The bitrate to encode at instead of a CRF.
+    bitrate: Option<u64>,
+    /// The complexity of the video, if it has already been measured.
+    complexity: Option<f64>,
+}
+
+impl VideoEncode {
+    /// Send an update about the encode, as one about its rendition or its
+    /// target if it is one.
+    fn report(
+        &self,
+        sender: &UnboundedSender<JobToOverseerMessage>,
+        message: JobToOverseerMessage,
+    ) {
+        let message = match self.rendition {
+            Some(idx) => {
+                JobToOverseerMessage::Rendition(idx, Box::new(message))
+            },
+            None => message,
+        };
+        let message = match self.target {
+            Some(idx) => JobToOverseerMessage::Target(idx, Box::new(message)),
+            None => message,
+        };
+
+        drop(sender.send(message));
+    }
+}
+
+/// Subtitles that are burned into the video, with the fonts they are rendered
+/// with.
+#[derive(Debug, Clone)]
+struct BurnIn {
+    /// The file of the subtitles, whose attached fonts the `subtitles` filter
+    /// loads by itself.
+    path: PathBuf,
+    /// The index of the subtitle stream within the file.
+    stream: usize,
+    /// The directory that the attachments of the video are dumped into, when
+    /// the subtitles come from another file, like an uploaded `.ass` that
+    /// is styled with the fonts attached to an MKV.
+    fonts_dir: Option<PathBuf>,
+}
+
+impl BurnIn {
+    /// Prepare the subtitles of the encode to be burned into the video from
+    /// the file.
+    async fn prepare(
+        inputs: &[ProbedInput],
+        video: usize,
+        encode: &VideoEncode,
+        env: &JobEnvironment,
+    ) -> Result<Option<BurnIn>, JobError> {
+        let source = match encode.settings.burn_subtitles {
+            Some(source) => source,
+            None => return Ok(None),
+        };
+        let fonts_dir = if source.file != video {
+            let fonts_dir = env.path(format!("{}-fonts", encode.name));
+            dump_attachments(&inputs[video].path, &fonts_dir, env).await?;
+            Some(fonts_dir)
+        }
+        else {
+            None
+        };
+
+        Ok(Some(BurnIn {
+            path: inputs[source.file].path.clone(),
+            stream: source.stream,
+            fonts_dir,
+        }))
+    }
+
+    /// The `subtitles` filter, which renders ASS with its styling and every
+    /// other text format with the default style.
+    fn filter(&self) -> String {
+        let mut filter = format!(
+            "subtitles=filename={}:si={}",
+            escape_filter_value(&self.path.to_string_lossy()),
+            self.stream
+        );
+        if let Some(fonts_dir) = &self.fonts_dir {
+            filter.push_str(&format!(
+                ":fontsdir={}",
+                escape_filter_value(&fonts_dir.to_string_lossy())
+            ));
+        }
+
+        filter
+    }
+}
+
+/// Dump the attachments of the file, like the fonts of an MKV, into the
+/// directory.
+async fn dump_attachments(
+    path: &Path,
+    dir: &Path,
+    env: &JobEnvironment,
+) -> Result<(), JobError> {
+    let step = "extracting the fonts of the video";
+
+    // attachments are dumped under their own names, relative to the current
+    // directory
+    tokio::fs::create_dir_all(dir)
+        .await
+        .map_err(|e| JobError::new(step, e.to_string()))?;
+    let path = path
+        .canonicalize()
+        .map_err(|e| JobError::new(step, e.to_string()))?;
+
+    // the output only makes FFmpeg open the file, which dumps the attachments
+    let mut command = env.ffmpeg();
+    command
+        .current_dir(dir)
+        .arg("-hide_banner")
+        .arg("-y")
+        .arg("-dump_attachment:t")
+        .arg("")
+        .arg("-i")
+        .arg(&path)
+        .arg("-t")
+        .arg("0")
+        .arg("-f")
+        .arg("null")
+        .arg("-");
+    run_ffmpeg(&mut command, step).await?;
+
+    Ok(())
+}
+
+/// Escape a value of a filter option, once for the option and once for the
+/// filter graph that it is in.
+fn escape_filter_value(value: &str) -> String {
+    let escape = |value: &str, special: &[char]| {
+        let mut escaped = String::with_capacity(value.len());
+        for c in value.chars() {
+            if c == '\\' || c == '\'' || special.contains(&c) {
+                escaped.push('\\');
+            }
+            escaped.push(c);
+        }
+        escaped
+    };
+
+    escape(&escape(value, &[':']), &['[', ']', ',', ';'])
+}
+
+async fn convert_video(
+    inputs: &[ProbedInput],
+    video: usize,
+    encode: &VideoEncode,
+    env: &JobEnvironment,
+    sender: UnboundedSender<JobToOverseerMessage>,
+) -> Result<PathBuf, JobError> {
+    let input = &inputs[video];
+    let path = &input.path;
+    let settings = &encode.settings;
+    let bitrate = encode.bitrate;
+    // the layout only has a video if the file has a video stream
+    let stream = input.video_stream().unwrap();
+    let frame_rate = stream.frame_rate();
+
+    // the CRF is from the dimensions of the output rather than the input's,
+    // which are the ones after rotating the video
+    let source = stream.display_dimensions();
+    let (width, height) = settings.output_dimensions(source.0, source.1);
+    let filter = settings.video_filter(source, (width, height));
+    let filter = filter.as_deref();
+    encode.report(
+        &sender,
+        JobToOverseerMessage::VideoDimensionsDetermined(width, height),
+    );
+
+    // both passes go through the same graph so that the stats of the first
+    // stay valid for the second
+    let burn_in = BurnIn::prepare(inputs, video, encode, env).await?;
+    let pass_filter = settings.pass_filter(filter, burn_in.as_ref());
+    let pass_filter = pass_filter.as_deref();
+
+    let crf_determine_future = async {
+        // the bitrate takes the place of the CRF
+        if let Some(bitrate) = bitrate {
+            encode.report(
+                &sender,
+                JobToOverseerMessage::VideoBitrateDetermined(bitrate),
+            );
+            return None;
+        }
+
+        let base = settings.codec.scale_crf(crf(width, height));
+        let choice = match settings.crf_mode {
+            CrfMode::Resolution => CrfChoice {
+                base,
+                adjusted: base,
+                complexity: None,
+                vmaf_target: None,
+                vmaf_failed: false,
+            },
+            CrfMode::Content => {
+                let complexity = match encode.complexity {
+                    Some(complexity) => Some(complexity),
+                    None => measure_complexity(&path, env).await,
+                };
+                CrfChoice {
+                    base,
+                    adjusted: complexity
+                        .map(|c| adjust_crf(base, c, settings.crf_band))
+                        .unwrap_or(base)
+                        .min(settings.codec.max_crf()),
+                    complexity,
+                    vmaf_target: None,
+                    vmaf_failed: false,
+                }
+            },
+            CrfMode::Vmaf => {
+                let samples = VmafSample::pick(input.duration);
+                encode.report(
+                    &sender,
+                    JobToOverseerMessage::VideoVmafSamplesChosen(
+                        samples.clone().into(),
+                    ),
+                );
+
+                let searched = search_vmaf_crf(
+                    &path,
+                    &samples,
+                    filter,
+                    (width, height),
+                    encode,
+                    env,
+                    &sender,
+                )
+                .await;
+                if searched.is_none() {
+                    eprintln!(
+                        "VMAF search of {} gave up on a sample, falling back \
+                         to CRF {}",
+                        encode.name, base
+                    );
+                }
+
+                CrfChoice {
+                    base,
+                    adjusted: searched.unwrap_or(base),
+                    complexity: None,
+                    vmaf_target: Some(settings.vmaf_target),
+                    vmaf_failed: searched.is_none(),
+                }
+            },
+        };
+        encode
+            .report(&sender, JobToOverseerMessage::VideoCrfDetermined(choice));
+
+        Some(choice.adjusted)
+    };
+
+    let first_pass_log = env.path(format!("{}-ffmpeg2pass-0.log", encode.name));
+    let output =
+        env.path(format!("{}.{}", encode.name, settings.codec.extension()));
+    let two_pass = bitrate.is_some() || settings.codec.two_pass_crf();
+
+    let first_pass_future = async {
+        if !two_pass {
+            encode
+                .report(&sender, JobToOverseerMessage::VideoFirstPassFinished);
+            return Ok(());
+        }
+
+        let mut command = env.ffmpeg();
+        command.arg("-hide_banner").arg("-i").arg(path);
+        settings.add_encoder_args(&mut command, pass_filter, bitrate);
+        settings.add_keyframe_args(&mut command, frame_rate);
+
+        command
+            .arg("-an")
+            .arg("-sn")
+            .arg("-pass")
+            .arg("1")
+            .arg("-passlogfile")
+            .arg(&first_pass_log)
+            .arg("-f")
+            .arg("null")
+            .arg("/dev/null");
+        run_ffmpeg(
+            &mut command,
+            &format!("encoding the first pass of {}", encode.name),
+        )
+        .await?;
+        encode.report(&sender, JobToOverseerMessage::VideoFirstPassFinished);
+
+        Ok(())
+    };
+
+    let (crf, first_pass) = join!(crf_determine_future, first_pass_future,);
+    first_pass?;
+
+    // TODO: add message here that conversion video conversion has started
+    // and send the supposed log file
+    let mut command = env.ffmpeg();
+    command.arg("-hide_banner").arg("-i").arg(path);
+    settings.add_encoder_args(&mut command, pass_filter, bitrate);
+    settings.add_keyframe_args(&mut command, frame_rate);
+
+    if let Some(crf) = crf {
+        command.arg("-crf").arg(crf.to_string());
+    }
+
+    if two_pass {
+        command
+            .arg("-pass")
+            .arg("2")
+            .arg("-passlogfile")
+            .arg(&first_pass_log);
+    }
+
+    command.arg("-an").arg("-sn").arg("-y").arg(&output);
+    run_ffmpeg(&mut command, &format!("encoding {}", encode.name)).await?;
+    encode.report(&sender, JobToOverseerMessage::VideoSecondPassFinished);
+
+    Ok(output)
+}
+
+//////// Ladder Section ////////////////////////////////////////////////////////
+
+/// The keyframe interval, in seconds, of the renditions of a ladder if none
+/// is given. It is also the duration of the segments.
+const LADDER_KEYFRAME_INTERVAL: f64 = 4.;
+
+/// The segments that the renditions of a ladder are packaged into.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SegmentFormat {
+    /// Fragmented MP4, which both DASH and HLS can play.
+    #[default]
+    Mp4,
+    /// WebM, which only DASH can play.
+    Webm,
+}
+
+impl SegmentFormat {
+    fn as_arg(self) -> &'static str {
+        match self {
+            SegmentFormat::Mp4 => "mp4",
+            SegmentFormat::Webm => "webm",
+        }
+    }
+
+    /// The muxer that the segments are written with.
+    pub fn muxer(self) -> &'static str {
+        self.as_arg()
+    }
+
+    fn container(self) -> Container {
+        match self {
+            SegmentFormat::Mp4 => Container::Mp4,
+            SegmentFormat::Webm => Container::WebM,
+        }
+    }
+}
+
+impl VideoSettings {
+    /// The settings of each rendition of the ladder given the height of the
+    /// input, tallest first.
+    ///
+    /// Renditions taller than the input are left out since they would only be
+    /// scaled up, unless none would be left, in which case the input's height
+    /// is used instead. Keyframes are placed at a fixed interval without any
+    /// at scene cuts, so that the segments of every rendition line up.
+    fn renditions(
+        &self,
+        height: usize,
+    ) -> Vec<VideoSettings> {
+        let mut heights = self
+            .ladder
+            .iter()
+            .copied()
+            .filter(|&h| h <= height)
+            .collect::<Vec<_>>();
+        if heights.is_empty() {
+            heights.push((height - height % 2).max(2));
+        }
+        heights.sort_unstable_by(|a, b| b.cmp(a));
+        heights.dedup();
+
+        heights
+            .into_iter()
+            .map(|h| VideoSettings {
+                target_height: Some(h),
+                keyframe_interval: Some(
+                    self.keyframe_interval.unwrap_or(LADDER_KEYFRAME_INTERVAL),
+                ),
+                scene_cut: false,
+                ladder: vec![],
+                ..self.clone()
+            })
+            .collect()
+    }
+}
+
+/// Encode every rendition of the ladder.
+///
+/// The complexity of the video is measured once for all of them, while their
+/// CRFs are each from their own dimensions.
+async fn convert_ladder(
+    inputs: &[ProbedInput],
+    video: usize,
+    settings: &VideoSettings,
+    env: &JobEnvironment,
+    sender: UnboundedSender<JobToOverseerMessage>,
+) -> Result<Vec<PathBuf>, JobError> {
+    let input = &inputs[video];
+    let height = input
+        .video_stream()
+        .map(|s| s.display_dimensions().1)
+        .unwrap_or(0);
+    let renditions = settings.renditions(height);
+    drop(sender.send(JobToOverseerMessage::VideoRenditionsPlanned(
+        renditions.len(),
+    )));
+
+    let complexity = match settings.crf_mode {
+        CrfMode::Content => measure_complexity(&input.path, env).await,
+        _ => None,
+    };
+
+    let encodes = renditions
+        .into_iter()
+        .enumerate()
+        .map(|(idx, settings)| VideoEncode {
+            name: format!("video-{}p", settings.target_height.unwrap_or(0)),
+            rendition: Some(idx),
+            target: None,
+            settings,
+            bitrate: None,
+            complexity,
+        })
+        .collect::<Vec<_>>();
+
+    futures::future::try_join_all(encodes.iter().map(|encode| {
+        convert_video(inputs, video, encode, env, sender.clone())
+    }))
+    .await
+}
+
+/// Package the renditions and the audio tracks for adaptive streaming, into a
+/// directory with a DASH manifest and, with MP4 segments, an HLS master
+/// playlist.
+///
+/// The directory is also archived as a tarball next to it, so that it can be
+/// downloaded as one file.
+async fn package_ladder(
+    audio: &[ConvertedAudioTrack],
+    renditions: Vec<PathBuf>,
+    settings: &VideoSettings,
+    env: &JobEnvironment,
+    sender: UnboundedSender<JobToOverseerMessage>,
+) -> Result<PathBuf, JobError> {
+    let package = env.path("package");
+    let manifest = package.join("manifest.mpd");
+    let hls = settings.segments == SegmentFormat::Mp4;
+
+    tokio::fs::create_dir_all(&package)
+        .await
+        .map_err(|e| JobError::new("packaging the ladder", e.to_string()))?;
+
+    let mut command = env.ffmpeg();
+    command.arg("-hide_banner").arg("-y");
+
+    for rendition in renditions.iter() {
+        command.arg("-i").arg(rendition);
+    }
+    for track in audio.iter() {
+        command.arg("-i").arg(&track.path);
+    }
+
+    for idx in 0 .. renditions.len() {
+        command.arg("-map").arg(format!("{}:v", idx));
+    }
+    for (idx, track) in audio.iter().enumerate() {
+        command
+            .arg("-map")
+            .arg(format!("{}:a", renditions.len() + idx));
+        add_track_metadata(&mut command, idx, track);
+    }
+
+    // the renditions are alternatives of each other, while each audio track
+    // is on its own since they can be of different languages
+    let mut adaptation_sets = "id=0,streams=v".to_owned();
+    for idx in 0 .. audio.len() {
+        adaptation_sets +=
+            &format!(" id={},streams={}", idx + 1, renditions.len() + idx);
+    }
+
+    let segment_duration = settings
+        .keyframe_interval
+        .unwrap_or(LADDER_KEYFRAME_INTERVAL);
+
+    command
+        .arg("-codec")
+        .arg("copy")
+        .arg("-f")
+        .arg("dash")
+        .arg("-seg_duration")
+        .arg(segment_duration.to_string())
+        .arg("-use_template")
+        .arg("1")
+        .arg("-use_timeline")
+        .arg("1")
+        .arg("-dash_segment_type")
+        .arg(settings.segments.as_arg())
+        .arg("-adaptation_sets")
+        .arg(adaptation_sets);
+
+    if hls {
+        command
+            .arg("-hls_playlist")
+            .arg("1")
+            .arg("-hls_master_name")
+            .arg("master.m3u8");
+    }
+
+    command.arg(&manifest);
+    run_ffmpeg(&mut command, "packaging the ladder").await?;
+
+    let archive = package.with_extension("tar");
+    let archived = {
+        let package = package.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut builder =
+                tar::Builder::new(std::fs::File::create(archive)?);
+            builder.append_dir_all("package", package)?;
+            builder.into_inner().map(drop)
+        })
+        .await
+    };
+    // the tarball is what is downloaded, so a package without one is of no use
+    archived
+        .map_err(|e| e.to_string())
+        .and_then(|archived| archived.map_err(|e| e.to_string()))
+        .map_err(|reason| JobError::new("archiving the package", reason))?;
+
+    drop(sender.send(JobToOverseerMessage::PackageWritten(
+        manifest,
+        hls.then(|| package.join("master.m3u8")),
+    )));
+
+    Ok(package)
+}
+
+//////// Subtitle Section //////////////////////////////////////////////////////
+
+/// What is done with a subtitle track in an output.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SubtitleDecision {
+    /// The track is copied as-is.
+    Copy,
+    /// The track is converted with the FFmpeg encoder, into the text format
+    /// of the container.
+    Convert(&'static str),
+    /// The output cannot carry the track.
+    Drop,
+}
+
+/// A subtitle track of the layout and what is done with it in an output.
+#[derive(Debug, Clone, Serialize)]
+pub struct SubtitleTrackPlan {
+    pub source: SubtitleSource,
+    /// The codec of the source stream.
+    pub codec: String,
+    pub decision: SubtitleDecision,
+}
+
+impl SubtitleTrackPlan {
+    /// Plan the subtitle tracks of the layout for an output in the container.
+    ///
+    /// Without a container, like for the package of a ladder or an output
+    /// without video, every track is dropped. So is the track that is burned
+    /// into the video of the output, if any, since it would be shown twice.
+    fn plan(
+        inputs: &[ProbedInput],
+        layout: &OutputLayout,
+        container: Option<Container>,
+        burned: Option<SubtitleSource>,
+    ) -> Vec<SubtitleTrackPlan> {
+        layout
+            .subtitles
+            .iter()
+            .map(|&source| {
+                let stream =
+                    &inputs[source.file].subtitle_streams[source.stream];
+
+                SubtitleTrackPlan {
+                    source,
+                    codec: stream.codec_name.clone(),
+                    decision: container
+                        .filter(|_| burned != Some(source))
+                        .map(|c| c.subtitle_decision(stream))
+                        .unwrap_or(SubtitleDecision::Drop),
+                }
+            })
+            .collect()
+    }
+}
+
+//////// Metadata Section //////////////////////////////////////////////////////
+
+/// Settings on the chapters, global tags and attachments of the output.
+#[derive(Debug, Clone, Default)]
+pub struct MetadataSettings {
+    /// The index of the uploaded file that the chapters, the global tags and,
+    /// for Matroska, the attachments are taken from. If `None`, it is the
+    /// file of the video, or the first file if there is no video.
+    pub source: Option<usize>,
+    /// Global tags that override those of the source, like `title`. An empty
+    /// value removes the tag.
+    pub tags: BTreeMap<String, String>,
+}
+
+impl MetadataSettings {
+    /// The uploaded file that the metadata is taken from.
+    fn source<'a>(
+        &self,
+        inputs: &'a [ProbedInput],
+        layout: &OutputLayout,
+    ) -> &'a ProbedInput {
+        &inputs[self.source.or(layout.video).unwrap_or(0)]
+    }
+
+    /// Add the arguments that take the chapters and the global tags from the
+    /// input at `idx`, with the tags of the settings overriding them.
+    fn add_args(
+        &self,
+        command: &mut Command,
+        idx: usize,
+    ) {
+        command
+            .arg("-map_metadata")
+            .arg(idx.to_string())
+            .arg("-map_chapters")
+            .arg(idx.to_string());
+
+        for (key, value) in self.tags.iter() {
+            command.arg("-metadata").arg(format!("{}={}", key, value));
+        }
+    }
+}
+
+/// Read the chapters, global tags and attachments that made it into the
+/// output.
+async fn probe_output_metadata(
+    output: &Path,
+    env: &JobEnvironment,
+) -> JobToOverseerMessage {
+    JobToOverseerMessage::OutputMetadataProbed(
+        determine_metadata(&env.ffprobe, output).await,
+    )
+}
+
+//////// Common Area ///////////////////////////////////////////////////////////
+
+/// A step of a job that has failed, which fails the whole job.
+#[derive(Debug, Clone)]
+pub struct JobError {
+    /// What the step was doing, like `"muxing the output"`.
+    step: String,
+    reason: String,
+}
+
+impl JobError {
+    fn new(
+        step: impl Into<String>,
+        reason: String,
+    ) -> JobError {
+        JobError {
+            step: step.into(),
+            reason,
+        }
+    }
+
+    pub fn write_error_msg<W>(
+        &self,
+        writer: &mut W,
+    ) -> Result<(), std::fmt::Error>
+    where
+        W: Write,
+    {
+        write!(writer, "Failed while {}: {}", self.step, self.reason)
+    }
+
+    pub fn as_error_msg(&self) -> String {
+        let mut retval = String::new();
+        self.write_error_msg(&mut retval).unwrap();
+        retval
+    }
+}
+
+/// Run an FFmpeg command to its end, failing the job at the step if FFmpeg
+/// cannot be started or does not succeed.
+///
+/// The last line that FFmpeg writes is kept as the reason, since it usually
+/// tells why.
+async fn run_ffmpeg(
+    command: &mut Command,
+    step: &str,
+) -> Result<Output, JobError> {
+    let output = command
+        .kill_on_drop(true)
+        .output()
+        .await
+        .map_err(|e| JobError::new(step, e.to_string()))?;
+    if output.status.success() {
+        return Ok(output);
+    }
+
+    let mut reason = match output.status.code() {
+        Some(code) => format!("FFmpeg exited with code {}", code),
+        None => "FFmpeg was killed".to_owned(),
+    };
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if let Some(line) = stderr.lines().rev().find(|l| !l.trim().is_empty()) {
+        reason += ": ";
+        reason += line.trim();
+    }
+
+    Err(JobError::new(step, reason))
+}
+
+/// What the converted video and audio tracks are muxed with, and into which
+/// container.
+struct MergePlan<'a> {
+    /// The subtitle tracks of the output, including the dropped ones.
+    subtitles: &'a [SubtitleTrackPlan],
+    /// The inputs that the subtitle tracks are taken from.
+    inputs: &'a [ProbedInput],
+    metadata: &'a MetadataSettings,
+    /// The input that the chapters, global tags and attachments are taken
+    /// from.
+    metadata_source: &'a ProbedInput,
+    container: Container,
+}
+
+/// Mux the converted video and audio tracks into the output, in the
+/// container of the plan, along with its subtitle tracks that are not dropped.
+///
+/// The audio tracks are placed in the same order as given, with only the
+/// default tracks being marked as such. Without any audio, only the video is
+/// muxed. The subtitle tracks are taken from their source files, keeping
+/// their metadata. The chapters and global tags are taken from the metadata
+/// source, as are its attachments and cover art if the output is Matroska,
+/// the only container that can carry them. MP4 outputs have their index
+/// moved to the front so that they can be played while they are still being
+/// downloaded.
+async fn merge_media(
+    audio: &[ConvertedAudioTrack],
+    video: &Path,
+    plan: &MergePlan<'_>,
+    output: &Path,
+    env: &JobEnvironment,
+) -> Result<(), JobError> {
+    let MergePlan {
+        subtitles,
+        inputs,
+        metadata,
+        metadata_source,
+        container,
+    } = *plan;
+    let subtitles = subtitles
+        .iter()
+        .filter(|track| track.decision != SubtitleDecision::Drop)
+        .collect::<Vec<_>>();
+
+    let mut command = env.ffmpeg();
+    command.arg("-hide_banner").arg("-y").arg("-i").arg(video);
+
+    for track in audio.iter() {
+        command.arg("-i").arg(&track.path);
+    }
+    for track in subtitles.iter() {
+        command.arg("-i").arg(&inputs[track.source.file].path);
+    }
+    let metadata_idx = audio.len() + 1 + subtitles.len();
+    command.arg("-i").arg(&metadata_source.path);
+
+    command.arg("-map").arg("0:v");
+
+    for (idx, track) in audio.iter().enumerate() {
+        command.arg("-map").arg(format!("{}:a", idx + 1));
+        add_track_metadata(&mut command, idx, track);
+    }
+    for (idx, track) in subtitles.iter().enumerate() {
+        command.arg("-map").arg(format!(
+            "{}:s:{}",
+            audio.len() + 1 + idx,
+            track.source.stream
+        ));
+    }
+
+    metadata.add_args(&mut command, metadata_idx);
+    if container == Container::Matroska {
+        // the attachments are optional so that files without any still map
+        command.arg("-map").arg(format!("{}:t?", metadata_idx));
+
+        // Matroska writes the cover art back as an attachment
+        if let Some(cover_art) = metadata_source.cover_art() {
+            command
+                .arg("-map")
+                .arg(format!("{}:v:{}", metadata_idx, cover_art))
+                .arg("-disposition:v:1")
+                .arg("attached_pic");
+        }
+    }
+
+    if container == Container::Mp4 {
+        command.arg("-movflags").arg("+faststart");
+    }
+
+    command.arg("-codec").arg("copy");
+
+    // the conversions override the copy for their tracks
+    for (idx, track) in subtitles.iter().enumerate() {
+        if let SubtitleDecision::Convert(encoder) = track.decision {
+            command.arg(format!("-codec:s:{}", idx)).arg(encoder);
+        }
+    }
+
+    command.arg("-f").arg(container.muxer()).arg(output);
+    run_ffmpeg(&mut command, "muxing the output").await?;
+
+    Ok(())
+}
+
+/// Mux the converted audio tracks of an input without any video.
+///
+/// The cover art of the input is written as a `METADATA_BLOCK_PICTURE` tag if
+/// the container allows it, and reported as dropped otherwise. The chapters
+/// and global tags are taken from the metadata source.
+async fn merge_audio_only(
+    audio: Option<Vec<ConvertedAudioTrack>>,
+    input: &ProbedInput,
+    container: AudioOnlyContainer,
+    metadata: &MetadataSettings,
+    metadata_source: &ProbedInput,
+    env: &JobEnvironment,
+    sender: UnboundedSender<JobToOverseerMessage>,
+) -> Result<PathBuf, JobError> {
+    let output = env.path(format!("output.{}", container.extension()));
+    let audio = audio.unwrap_or_default();
+
+    let cover_art = match input.cover_art() {
+        Some(stream) if container.allows_cover_art() => {
+            Some(write_cover_art_metadata(input, stream, env).await?)
+        },
+        Some(_) => {
+            drop(sender.send(JobToOverseerMessage::CoverArtDropped));
+            None
+        },
+        None => None,
+    };
+
+    let mut command = env.ffmpeg();
+    command.arg("-hide_banner").arg("-y");
+
+    for track in audio.iter() {
+        command.arg("-i").arg(&track.path);
+    }
+
+    let metadata_idx = audio.len();
+    command.arg("-i").arg(&metadata_source.path);
+
+    if let Some(cover_art) = &cover_art {
+        command.arg("-f").arg("ffmetadata").arg("-i").arg(cover_art);
+    }
+
+    for (idx, track) in audio.iter().enumerate() {
+        command.arg("-map").arg(format!("{}:a", idx));
+        add_track_metadata(&mut command, idx, track);
+    }
+
+    metadata.add_args(&mut command, metadata_idx);
+
+    // FFmpeg merges the global tags of both files, the earlier mapping
+    // winning on the keys they share
+    if cover_art.is_some() {
+        command
+            .arg("-map_metadata")
+            .arg((metadata_idx + 1).to_string());
+    }
+
+    command.arg("-codec").arg("copy").arg(&output);
+    run_ffmpeg(&mut command, "muxing the output").await?;
+    drop(sender.send(JobToOverseerMessage::VideoConversionFinished));
+
+    Ok(output)
+}
+
+/// Write the cover art at the given video stream index of the input as an
+/// FFmetadata file with a `METADATA_BLOCK_PICTURE` tag, which is how Ogg files
+/// carry pictures. Its value is too long to be passed as an argument.
+async fn write_cover_art_metadata(
+    input: &ProbedInput,
+    stream: usize,
+    env: &JobEnvironment,
+) -> Result<PathBuf, JobError> {
+    let info = &input.video_streams[stream];
+    let picture = env.path("cover-art");
+
+    // players only take JPEG and PNG pictures, so the others are converted
+    let (mime_type, codec) = match info.picture_mime_type() {
+        Some(mime_type) => (mime_type, "copy"),
+        None => ("image/png", "png"),
+    };
+
+    let mut command = env.ffmpeg();
+    command
+        .arg("-hide_banner")
+        .arg("-y")
+        .arg("-i")
+        .arg(&input.path)
+        .arg("-map")
+        .arg(format!("0:v:{}", stream))
+        .arg("-frames:v")
+        .arg("1")
+        .arg("-codec:v")
+        .arg(codec)
+        .arg("-f")
+        .arg("image2")
+        .arg("-update")
+        .arg("1")
+        .arg(&picture);
+    run_ffmpeg(&mut command, "extracting the cover art").await?;
+
+    let data = tokio::fs::read(&picture).await.map_err(|e| {
+        JobError::new("extracting the cover art", e.to_string())
+    })?;
+    let block = flac_picture_block(mime_type, (info.width, info.height), &data);
+
+    let metadata = env.path("cover-art.ffmetadata");
+    let contents = format!(
+        ";FFMETADATA1\nMETADATA_BLOCK_PICTURE={}\n",
+        escape_ffmetadata(&base64(&block)),
+    );
+    tokio::fs::write(&metadata, contents)
+        .await
+        .map_err(|e| JobError::new("writing the cover art", e.to_string()))?;
+
+    Ok(metadata)
+}
+
+/// The picture block of a FLAC file for a front cover, without the header of
+/// the block.
+fn flac_picture_block(
+    mime_type: &str,
+    (width, height): (usize, usize),
+    data: &[u8],
+) -> Vec<u8> {
+    const FRONT_COVER: u32 = 3;
+
+    let mut block = Vec::with_capacity(32 + mime_type.len() + data.len());
+    block.extend(FRONT_COVER.to_be_bytes());
+    block.extend((mime_type.len() as u32).to_be_bytes());
+    block.extend(mime_type.as_bytes());
+    // no description
+    block.extend(0u32.to_be_bytes());
+    block.extend((width as u32).to_be_bytes());
+    block.extend((height as u32).to_be_bytes());
+    // the color depth and the size of the palette are unknown
+    block.extend(0u32.to_be_bytes());
+    block.extend(0u32.to_be_bytes());
+    block.extend((data.len() as u32).to_be_bytes());
+    block.extend(data);
+
+    block
+}
+
+/// Encode bytes as standard, padded base64.
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] =
+        b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ];
+        let n = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+
+        for i in 0 .. 4 {
+            if i <= chunk.len() {
+                let sextet = (n >> (18 - 6 * i)) & 0x3f;
+                encoded.push(ALPHABET[sextet as usize] as char);
+            }
+            else {
+                encoded.push('=');
+            }
+        }
+    }
+
+    encoded
+}
+
+/// Escape a value of an FFmetadata file.
+fn escape_ffmetadata(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '=' | ';' | '#' | '\\' | '\n') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+
+    escaped
+}
+
+/// Add the disposition and the metadata of the output audio track at `idx`.
+fn add_track_metadata(
+    command: &mut Command,
+    idx: usize,
+    track: &ConvertedAudioTrack,
+) {
+    let disposition = if track.default { "default" } else { "0" };
+    command
+        .arg(format!("-disposition:a:{}", idx))
+        .arg(disposition);
+
+    if let Some(language) = &track.language {
+        command
+            .arg(format!("-metadata:s:a:{}", idx))
+            .arg(format!("language={}", language));
+    }
+
+    if let Some(title) = &track.title {
+        command
+            .arg(format!("-metadata:s:a:{}", idx))
+            .arg(format!("title={}", title));
+    }
+}
+
+/// The container of the output when the input has no video.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AudioOnlyContainer {
+    /// Ogg, with the `.opus` extension.
+    Opus,
+    /// Ogg, with the `.ogg` extension.
+    Ogg,
+    /// WebM without a video track.
+    WebM,
+}
+
+impl AudioOnlyContainer {
+    fn extension(self) -> &'static str {
+        match self {
+            AudioOnlyContainer::Opus => "opus",
+            AudioOnlyContainer::Ogg => "ogg",
+            AudioOnlyContainer::WebM => "webm",
+        }
+    }
+
+    /// The name of the FFmpeg muxer of the container.
+    pub fn muxer(self) -> &'static str {
+        // the extensions happen to be the names of the muxers
+        self.extension()
+    }
+
+    /// Whether the container can carry a cover art. Ogg takes it as a tag,
+    /// while WebM allows neither attachments nor image tracks.
+    fn allows_cover_art(self) -> bool {
+        match self {
+            AudioOnlyContainer::Opus | AudioOnlyContainer::Ogg => true,
+            AudioOnlyContainer::WebM => false,
+        }
+    }
+}
+
+/// Where a job keeps its files and which binaries it runs.
+#[derive(Debug, Clone)]
+pub struct JobEnvironment {
+    /// The directory of the uploads, the intermediate files and the output of
+    /// the job.
+    pub dir: PathBuf,
+    pub ffmpeg: PathBuf,
+    pub ffprobe: PathBuf,
+}
+
+impl JobEnvironment {
+    fn ffmpeg(&self) -> Command {
+        Command::new(&self.ffmpeg)
+    }
+
+    /// The path of a file in the directory of the job.
+    fn path(
+        &self,
+        name: impl AsRef<Path>,
+    ) -> PathBuf {
+        self.dir.join(name)
+    }
+}
+
+/// Settings of a job, resolved from what the client has requested.
+#[derive(Debug, Clone)]
+pub struct JobSettings {
+    pub audio: AudioSettings,
+    pub video: VideoSettings,
+    /// The container of the output if the input turns out to have no video.
+    pub audio_only_container: AudioOnlyContainer,
+    /// Outputs besides the main one, like a fallback in an older codec.
+    pub targets: Vec<OutputTarget>,
+    /// Whether to keep the subtitle tracks of the layout.
+    pub keep_subtitles: bool,
+    pub metadata: MetadataSettings,
+}
+
+impl Default for JobSettings {
+    fn default() -> JobSettings {
+        JobSettings {
+            audio: AudioSettings::default(),
+            video: VideoSettings::default(),
+            audio_only_container: AudioOnlyContainer::Opus,
+            targets: vec![],
+            keep_subtitles: true,
+            metadata: MetadataSettings::default(),
+        }
+    }
+}
+
+impl JobSettings {
+    /// The video settings of the main output followed by those of every
+    /// extra target.
+    pub fn all_video(&self) -> impl Iterator<Item = &VideoSettings> {
+        std::iter::once(&self.video)
+            .chain(self.targets.iter().map(|target| &target.video))
+    }
+}
+
+/// An extra output of a job, with the video encoded from the same input with
+/// its own settings, muxed with the same audio tracks as the main output.
+///
+/// A target never has a ladder, which only the main output can have.
+#[derive(Debug, Clone)]
+pub struct OutputTarget {
+    /// What the output is named after and downloaded as.
+    pub name: String,
+    pub video: VideoSettings,
+}
+
+/// What a job has written once it has finished.
+#[derive(Debug, Clone)]
+pub struct JobOutputs {
+    /// The main output, which is a directory for a ladder.
+    pub main: PathBuf,
+    /// The outputs of the extra targets, by their names. Jobs without video
+    /// have none.
+    pub targets: HashMap<String, PathBuf>,
+}
+
+/// The status of one encode of the video.
+#[derive(Debug, Clone, Serialize)]
+pub struct VideoStatus {
+    pass: AudioVideoStatus,
+    dimensions: Option<(usize, usize)>,
+    crf: Option<CrfChoice>,
+    vmaf_samples: Option<Arc<[VmafSample]>>,
+    vmaf_trials: Vec<VmafTrial>,
+    /// The bitrate that the video is encoded at to fit the target size.
+    bitrate: Option<u64>,
+}
+
+impl VideoStatus {
+    fn new() -> VideoStatus {
+        VideoStatus {
+            pass: AudioVideoStatus::FirstPass,
+            dimensions: None,
+            crf: None,
+            vmaf_samples: None,
+            vmaf_trials: vec![],
+            bitrate: None,
+        }
+    }
+
+    fn process_update(
+        &mut self,
+        update: JobToOverseerMessage,
+    ) {
+        use JobToOverseerMessage::*;
+
+        match update {
+            VideoFirstPassFinished => self.pass = AudioVideoStatus::SecondPass,
+            VideoSecondPassFinished => self.pass = AudioVideoStatus::Finished,
+            VideoDimensionsDetermined(width, height) => {
+                self.dimensions = Some((width, height))
+            },
+            VideoCrfDetermined(crf) => self.crf = Some(crf),
+            VideoVmafSamplesChosen(samples) => {
+                self.vmaf_samples = Some(samples)
+            },
+            VideoVmafTrialFinished(trial) => self.vmaf_trials.push(trial),
+            VideoBitrateDetermined(bitrate) => self.bitrate = Some(bitrate),
+
+            _ => {},
+        }
+    }
+}
+
+/// The status of an extra target.
+#[derive(Debug, Clone, Serialize)]
+pub struct TargetStatus {
+    name: String,
+    video: VideoStatus,
+    subtitles: Option<Arc<[SubtitleTrackPlan]>>,
+    /// Whether the output has been muxed.
+    merged: bool,
+    output_size: Option<u64>,
+    target_size_met: Option<bool>,
+    output_metadata: Option<FileMetadata>,
+}
+
+impl TargetStatus {
+    fn new(name: String) -> TargetStatus {
+        TargetStatus {
+            name,
+            video: VideoStatus::new(),
+            subtitles: None,
+            merged: false,
+            output_size: None,
+            target_size_met: None,
+            output_metadata: None,
+        }
+    }
+
+    fn process_update(
+        &mut self,
+        update: JobToOverseerMessage,
+    ) {
+        use JobToOverseerMessage::*;
+
+        match update {
+            VideoConversionFinished => self.merged = true,
+            SubtitlesPlanned(subtitles) => self.subtitles = Some(subtitles),
+            OutputSizeChecked(size, target_met) => {
+                self.output_size = Some(size);
+                self.target_size_met = target_met;
+            },
+            OutputMetadataProbed(metadata) => {
+                self.output_metadata = Some(metadata)
+            },
+
+            update => self.video.process_update(update),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct JobStatus {
+    /// Whether the job has finished, successfully or not.
+    finished: bool,
+    /// Why the job has failed, if it has.
+    error: Option<String>,
+
+    audio: AudioVideoStatus,
+    /// The video, or the whole ladder if there is one.
+    video: VideoStatus,
+
+    layout_case: &'static str,
+
+    audio_tracks: Option<Arc<[AudioTrackPlan]>>,
+    audio_constants: Option<Arc<[AudioConstants]>>,
+    audio_decisions: Option<Arc<[AudioTrackDecision]>>,
+    audio_gains: Option<Arc<[Option<AudioGain>]>>,
+    /// The subtitle tracks and whether they are kept in the output.
+    subtitles: Option<Arc<[SubtitleTrackPlan]>>,
+    /// Whether the cover art of the input has been left out of the output.
+    cover_art_dropped: bool,
+    /// The renditions of the ladder, tallest first.
+    renditions: Vec<VideoStatus>,
+    /// The DASH manifest of the ladder, once it has been packaged.
+    #[serde(serialize_with = "file_name")]
+    manifest: Option<PathBuf>,
+    /// The HLS master playlist of the ladder, if it has one.
+    #[serde(serialize_with = "file_name")]
+    hls_master: Option<PathBuf>,
+    /// The size of the output once muxed, in bytes.
+    output_size: Option<u64>,
+    /// Whether the output fits in the target size, if there is one.
+    target_size_met: Option<bool>,
+    /// What the output has kept of the chapters, global tags and
+    /// attachments, unless it is a ladder.
+    output_metadata: Option<FileMetadata>,
+    /// The extra targets, in the same order as in the settings.
+    targets: Vec<TargetStatus>,
+
+    #[serde(skip)]
+    video_conversion_log_path: Option<PathBuf>,
+}
+
+/// Serialize a file of the job as only its name, which is what it is
+/// downloaded by, rather than as its path on the server.
+fn file_name<S>(
+    path: &Option<PathBuf>,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    path.as_deref()
+        .and_then(Path::file_name)
+        .map(|name| name.to_string_lossy())
+        .serialize(serializer)
 }
 
 impl JobStatus {
-    fn new() -> JobStatus {
+    pub fn new(
+        layout_case: LayoutCase,
+        targets: &[OutputTarget],
+    ) -> JobStatus {
         JobStatus {
+            finished: false,
+            error: None,
+
             audio: AudioVideoStatus::FirstPass,
-            video: AudioVideoStatus::FirstPass,
+            video: VideoStatus::new(),
+
+            layout_case: layout_case.description(),
 
+            audio_tracks: None,
             audio_constants: None,
-            dimensions: None,
-            crf: None,
+            audio_decisions: None,
+            audio_gains: None,
+            subtitles: None,
+            cover_art_dropped: false,
+            renditions: vec![],
+            manifest: None,
+            hls_master: None,
+            output_size: None,
+            target_size_met: None,
+            output_metadata: None,
+            targets: targets
+                .iter()
+                .map(|target| TargetStatus::new(target.name.clone()))
+                .collect(),
 
             video_conversion_log_path: None,
         }
     }
 
+    /// Mark the job as finished, with why it has failed if it has.
+    pub fn finish(
+        &mut self,
+        error: Option<&JobError>,
+    ) {
+        self.finished = true;
+        self.error = error.map(JobError::as_error_msg);
+    }
+
     fn process_update(
         &mut self,
         update: JobToOverseerMessage,
@@ -436,74 +3480,488 @@ impl JobStatus {
         // back to first passes?
         match update {
             AudioSecondPassFinished => self.audio = AudioVideoStatus::Finished,
-            VideoFirstPassFinished => self.video = AudioVideoStatus::SecondPass,
-            VideoSecondPassFinished => self.video = AudioVideoStatus::Finished,
+            NoVideoStream => {
+                self.video.pass = AudioVideoStatus::NotApplicable;
+                for target in self.targets.iter_mut() {
+                    target.video.pass = AudioVideoStatus::NotApplicable;
+                }
+            },
+            NoAudioStream => self.audio = AudioVideoStatus::NotApplicable,
+            CoverArtDropped => self.cover_art_dropped = true,
 
+            AudioTracksPlanned(audio_tracks) => {
+                self.audio_tracks = Some(audio_tracks)
+            },
             AudioConstantsDetermined(audio_constants) => {
                 self.audio_constants = Some(audio_constants)
             },
-            VideoDimensionsDetermined(width, height) => {
-                self.dimensions = Some((width, height))
+            AudioDecisionsDetermined(audio_decisions) => {
+                self.audio_decisions = Some(audio_decisions)
+            },
+            AudioGainsDetermined(audio_gains) => {
+                self.audio_gains = Some(audio_gains)
+            },
+            SubtitlesPlanned(subtitles) => self.subtitles = Some(subtitles),
+            VideoRenditionsPlanned(count) => {
+                self.renditions = vec![VideoStatus::new(); count]
+            },
+            Rendition(idx, update) => {
+                if let Some(rendition) = self.renditions.get_mut(idx) {
+                    rendition.process_update(*update);
+                }
+            },
+            PackageWritten(manifest, hls_master) => {
+                self.video.pass = AudioVideoStatus::Finished;
+                self.manifest = Some(manifest);
+                self.hls_master = hls_master;
+            },
+            OutputSizeChecked(size, target_met) => {
+                self.output_size = Some(size);
+                self.target_size_met = target_met;
+            },
+            OutputMetadataProbed(metadata) => {
+                self.output_metadata = Some(metadata)
+            },
+            Target(idx, update) => {
+                if let Some(target) = self.targets.get_mut(idx) {
+                    target.process_update(*update);
+                }
             },
-            VideoCrfDetermined(crf) => self.crf = Some(crf),
 
             VideoSecondPassProgress(path) => {
                 self.video_conversion_log_path = Some(path)
             },
 
-            _ => {},
+            update => self.video.process_update(update),
         }
     }
 }
 
+/// Check the size of an output against the target size, if the output is a
+/// file. A package is a directory, whose size is not checked.
+async fn check_output_size(
+    output: &Path,
+    target_size: Option<usize>,
+) -> Option<JobToOverseerMessage> {
+    let metadata = tokio::fs::metadata(output).await.ok()?;
+    let size = metadata.is_file().then_some(metadata.len())?;
+
+    Some(JobToOverseerMessage::OutputSizeChecked(
+        size,
+        target_size.map(|t| size <= t as u64),
+    ))
+}
+
 /// The future that is returned by `run_job`.
 pub(crate) async fn actually_run_job(
-    path: impl AsRef<OsStr>,
-    status_sender: UnboundedSender<JobStatus>,
-    mut request_receiver: UnboundedReceiver<RequestForJobStatus>,
-) -> PathBuf {
+    inputs: Vec<ProbedInput>,
+    layout: OutputLayout,
+    settings: JobSettings,
+    env: JobEnvironment,
+    status: Arc<Mutex<JobStatus>>,
+) -> Result<JobOutputs, JobError> {
     let (update_sender, mut update_receiver) = unbounded_channel();
 
-    let main_job_future = async {
-        let (audio_files, video_file) = join!(
-            convert_audio(&path, update_sender.clone()),
-            convert_video(&path, update_sender.clone()),
+    // the updates end once the job does, as it owns every sender of them
+    let main_job_future = async move {
+        let audio_future = convert_audio(
+            &inputs,
+            &layout,
+            &settings.audio,
+            &env,
+            update_sender.clone(),
         );
 
-        let merged = merge_media(audio_files, video_file, update_sender).await;
+        // the bitrates have been checked before the job has been made
+        let target_encodes = settings
+            .targets
+            .iter()
+            .enumerate()
+            .map(|(idx, target)| VideoEncode {
+                name: format!("video-target-{}", target.name),
+                rendition: None,
+                target: Some(idx),
+                settings: target.video.clone(),
+                bitrate: target_video_bitrate(
+                    &inputs,
+                    &layout,
+                    &settings.audio,
+                    &target.video,
+                )
+                .ok()
+                .flatten(),
+                complexity: None,
+            })
+            .collect::<Vec<_>>();
+
+        // the targets are encoded along with the main output, from the same
+        // probe and the same audio tracks
+        let targets_future = |video: usize| {
+            futures::future::try_join_all(target_encodes.iter().map(|encode| {
+                convert_video(
+                    &inputs,
+                    video,
+                    encode,
+                    &env,
+                    update_sender.clone(),
+                )
+            }))
+        };
+
+        let plan_subtitles =
+            |container: Option<Container>, burned: Option<SubtitleSource>| {
+                Arc::<[SubtitleTrackPlan]>::from(SubtitleTrackPlan::plan(
+                    &inputs, &layout, container, burned,
+                ))
+            };
+
+        let metadata_source = settings.metadata.source(&inputs, &layout);
+
+        let (audio_files, main, target_videos) = if let Some(video) =
+            layout.video.filter(|_| !settings.video.ladder.is_empty())
+        {
+            let (audio_files, renditions, target_videos) = try_join!(
+                audio_future,
+                convert_ladder(
+                    &inputs,
+                    video,
+                    &settings.video,
+                    &env,
+                    update_sender.clone()
+                ),
+                targets_future(video),
+            )?;
+            let audio_files = audio_files.unwrap_or_default();
+
+            drop(update_sender.send(JobToOverseerMessage::SubtitlesPlanned(
+                plan_subtitles(None, None),
+            )));
+
+            let package = package_ladder(
+                &audio_files,
+                renditions,
+                &settings.video,
+                &env,
+                update_sender.clone(),
+            )
+            .await?;
+
+            (audio_files, package, target_videos)
+        }
+        else if let Some(video) = layout.video {
+            let encode = VideoEncode {
+                name: "video".to_owned(),
+                rendition: None,
+                target: None,
+                settings: settings.video.clone(),
+                bitrate: target_video_bitrate(
+                    &inputs,
+                    &layout,
+                    &settings.audio,
+                    &settings.video,
+                )
+                .ok()
+                .flatten(),
+                complexity: None,
+            };
+
+            let (audio_files, video_file, target_videos) = try_join!(
+                audio_future,
+                convert_video(
+                    &inputs,
+                    video,
+                    &encode,
+                    &env,
+                    update_sender.clone()
+                ),
+                targets_future(video),
+            )?;
+            let audio_files = audio_files.unwrap_or_default();
+
+            let container = settings.video.output_container();
+            let subtitles =
+                plan_subtitles(Some(container), settings.video.burn_subtitles);
+            drop(update_sender.send(JobToOverseerMessage::SubtitlesPlanned(
+                subtitles.clone(),
+            )));
+
+            let output = env.path(format!("output.{}", container.extension()));
+            let plan = MergePlan {
+                subtitles: &subtitles,
+                inputs: &inputs,
+                metadata: &settings.metadata,
+                metadata_source,
+                container,
+            };
+            merge_media(&audio_files, &video_file, &plan, &output, &env)
+                .await?;
+            drop(
+                update_sender
+                    .send(JobToOverseerMessage::VideoConversionFinished),
+            );
+            drop(
+                update_sender.send(probe_output_metadata(&output, &env).await),
+            );
+
+            (audio_files, output, target_videos)
+        }
+        else {
+            drop(update_sender.send(JobToOverseerMessage::NoVideoStream));
+            drop(update_sender.send(JobToOverseerMessage::SubtitlesPlanned(
+                plan_subtitles(None, None),
+            )));
+
+            let audio_files = audio_future.await?;
+
+            // the cover art is only taken from the first file
+            let output = merge_audio_only(
+                audio_files,
+                &inputs[0],
+                settings.audio_only_container,
+                &settings.metadata,
+                metadata_source,
+                &env,
+                update_sender.clone(),
+            )
+            .await?;
+            drop(
+                update_sender.send(probe_output_metadata(&output, &env).await),
+            );
+
+            (vec![], output, vec![])
+        };
+
+        if let Some(checked) =
+            check_output_size(&main, settings.video.target_size).await
+        {
+            drop(update_sender.send(checked));
+        }
+
+        let mut targets = HashMap::new();
+        for ((target, encode), video_file) in settings
+            .targets
+            .iter()
+            .zip(target_encodes.iter())
+            .zip(target_videos)
+        {
+            let container = target.video.output_container();
+            let subtitles =
+                plan_subtitles(Some(container), target.video.burn_subtitles);
+            encode.report(
+                &update_sender,
+                JobToOverseerMessage::SubtitlesPlanned(subtitles.clone()),
+            );
+
+            let output = env.path(format!(
+                "output-{}.{}",
+                target.name,
+                container.extension()
+            ));
+            let plan = MergePlan {
+                subtitles: &subtitles,
+                inputs: &inputs,
+                metadata: &settings.metadata,
+                metadata_source,
+                container,
+            };
+            merge_media(&audio_files, &video_file, &plan, &output, &env)
+                .await?;
+            encode.report(
+                &update_sender,
+                JobToOverseerMessage::VideoConversionFinished,
+            );
+            encode.report(
+                &update_sender,
+                probe_output_metadata(&output, &env).await,
+            );
+
+            if let Some(checked) =
+                check_output_size(&output, target.video.target_size).await
+            {
+                encode.report(&update_sender, checked);
+            }
+
+            targets.insert(target.name.clone(), output);
+        }
 
         // TODO: delete temporary files
-        merged
+        Ok::<_, JobError>(JobOutputs { main, targets })
     };
 
     let message_processor_future = async {
-        // TODO: to prevent DDOS attacks, use Arc<RwLock<_>>
-        let mut state = JobStatus::new();
-
-        loop {
-            select! {
-                biased;
-
-                // receive updates from our job
-                message = update_receiver.recv() => {
-                    let message = match message {
-                        Some(m) => m,
-                        None => break,
-                    };
-
-                    state.process_update(message);
-                },
-
-                // receive request for updates from caller
-                _ = request_receiver.recv() => {
-                    drop(status_sender.send(state.clone()));
-                },
-            }
+        while let Some(message) = update_receiver.recv().await {
+            status.lock().unwrap().process_update(message);
         }
     };
 
-    select! {
-        retval = main_job_future => return retval,
-        _ = message_processor_future => unreachable!(),
+    let (outputs, _) = join!(main_job_future, message_processor_future);
+    outputs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn output_dimensions_keep_even_dimensions() {
+        let settings = VideoSettings::default();
+
+        assert_eq!(settings.output_dimensions(1920, 1080), (1920, 1080));
+    }
+
+    #[test]
+    fn output_dimensions_round_odd_dimensions_to_even() {
+        let settings = VideoSettings::default();
+
+        assert_eq!(settings.output_dimensions(641, 361), (642, 362));
+        assert_eq!(settings.output_dimensions(1, 1), (2, 2));
+        assert_eq!(settings.output_dimensions(0, 0), (2, 2));
+    }
+
+    #[test]
+    fn output_dimensions_fit_the_maximum_dimensions() {
+        let settings = VideoSettings {
+            max_height: Some(720),
+            ..VideoSettings::default()
+        };
+        assert_eq!(settings.output_dimensions(1920, 1080), (1280, 720));
+        assert_eq!(settings.output_dimensions(640, 360), (640, 360));
+
+        let settings = VideoSettings {
+            max_height: Some(720),
+            max_width: Some(720),
+            ..VideoSettings::default()
+        };
+        assert_eq!(settings.output_dimensions(1920, 1080), (720, 406));
+        assert_eq!(settings.output_dimensions(1080, 1920), (406, 720));
+    }
+
+    #[test]
+    fn output_dimensions_scale_to_the_target_height() {
+        let settings = VideoSettings {
+            target_height: Some(480),
+            ..VideoSettings::default()
+        };
+
+        assert_eq!(settings.output_dimensions(1920, 1080), (854, 480));
+        assert_eq!(settings.output_dimensions(640, 360), (854, 480));
+    }
+
+    #[test]
+    fn adjust_crf_keeps_the_base_at_the_reference_complexity() {
+        assert_eq!(adjust_crf(31, REFERENCE_COMPLEXITY, 6), 31);
+    }
+
+    #[test]
+    fn adjust_crf_moves_by_2_per_doubling() {
+        assert_eq!(adjust_crf(31, REFERENCE_COMPLEXITY * 2., 6), 29);
+        assert_eq!(adjust_crf(31, REFERENCE_COMPLEXITY * 4., 6), 27);
+        assert_eq!(adjust_crf(31, REFERENCE_COMPLEXITY / 2., 6), 33);
+    }
+
+    #[test]
+    fn adjust_crf_stays_within_the_band_and_the_crf_range() {
+        assert_eq!(adjust_crf(31, 255., 6), 25);
+        assert_eq!(adjust_crf(31, 0., 6), 37);
+        assert_eq!(adjust_crf(31, 0., 0), 31);
+        assert_eq!(adjust_crf(2, 255., 6), 0);
+        assert_eq!(adjust_crf(60, 0., 6), 63);
+    }
+
+    #[test]
+    fn vmaf_samples_spread_across_long_videos() {
+        let samples = VmafSample::pick(Some(100.));
+
+        assert_eq!(samples.len(), VMAF_SAMPLE_COUNT);
+        assert_eq!(samples[0].start, 23.);
+        assert_eq!(samples[2].start, 73.);
+        assert!(samples.iter().all(|s| s.length == VMAF_SAMPLE_LENGTH));
+    }
+
+    #[test]
+    fn vmaf_samples_stay_inside_videos_just_long_enough() {
+        let duration = VMAF_SAMPLE_COUNT as f64 * VMAF_SAMPLE_LENGTH + 0.1;
+        let samples = VmafSample::pick(Some(duration));
+
+        assert_eq!(samples.len(), VMAF_SAMPLE_COUNT);
+        assert!(samples
+            .iter()
+            .all(|s| s.start >= 0. && s.start + s.length <= duration));
+    }
+
+    #[test]
+    fn vmaf_samples_cover_short_videos_once() {
+        let samples = VmafSample::pick(Some(5.));
+        assert_eq!(samples.len(), 1);
+        assert_eq!((samples[0].start, samples[0].length), (0., 5.));
+
+        let samples = VmafSample::pick(Some(0.5));
+        assert_eq!((samples[0].start, samples[0].length), (0., 0.5));
+    }
+
+    #[test]
+    fn vmaf_samples_start_at_0_without_a_duration() {
+        for duration in [None, Some(0.), Some(-1.)] {
+            let samples = VmafSample::pick(duration);
+
+            assert_eq!(samples.len(), 1);
+            assert_eq!(
+                (samples[0].start, samples[0].length),
+                (0., VMAF_SAMPLE_LENGTH)
+            );
+        }
+    }
+
+    #[test]
+    fn escape_filter_value_escapes_option_separators_twice() {
+        assert_eq!(escape_filter_value("subs.srt"), "subs.srt");
+        assert_eq!(escape_filter_value("a:b.srt"), r"a\\:b.srt");
+        assert_eq!(escape_filter_value(r"C:\subs.srt"), r"C\\:\\\\subs.srt");
+    }
+
+    #[test]
+    fn escape_filter_value_escapes_quotes_twice() {
+        assert_eq!(escape_filter_value("it's.srt"), r"it\\\'s.srt");
+    }
+
+    #[test]
+    fn escape_filter_value_escapes_graph_separators_once() {
+        assert_eq!(escape_filter_value("a,b;c.srt"), r"a\,b\;c.srt");
+        assert_eq!(escape_filter_value("[sub].ass"), r"\[sub\].ass");
+        assert_eq!(
+            escape_filter_value("it's, a: [sub].ass"),
+            r"it\\\'s\, a\\: \[sub\].ass"
+        );
+    }
+
+    #[test]
+    fn base64_pads_the_last_chunk() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+        assert_eq!(base64(&[0xfb, 0xff]), "+/8=");
+    }
+
+    #[test]
+    fn flac_picture_block_describes_a_front_cover() {
+        let block = flac_picture_block("image/png", (600, 400), b"png");
+
+        let mut expected = vec![0, 0, 0, 3, 0, 0, 0, 9];
+        expected.extend(b"image/png");
+        expected.extend([0, 0, 0, 0]);
+        expected.extend([0, 0, 2, 88, 0, 0, 1, 144]);
+        expected.extend([0; 8]);
+        expected.extend([0, 0, 0, 3]);
+        expected.extend(b"png");
+        assert_eq!(block, expected);
+    }
+
+    #[test]
+    fn escape_ffmetadata_escapes_special_characters() {
+        assert_eq!(escape_ffmetadata("Zm9v"), "Zm9v");
+        assert_eq!(escape_ffmetadata("Zg=="), r"Zg\=\=");
+        assert_eq!(escape_ffmetadata("a;b#c\\d\ne"), "a\\;b\\#c\\\\d\\\ne");
     }
 }
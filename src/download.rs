@@ -0,0 +1,131 @@
+use std::{
+    ffi::OsStr,
+    path::{
+        Component,
+        Path,
+        PathBuf,
+    },
+};
+
+use axum::{
+    body::{
+        boxed,
+        StreamBody,
+    },
+    http::{
+        header,
+        StatusCode,
+    },
+    response::Response,
+};
+use tokio_util::io::ReaderStream;
+
+use crate::error_responses::HttpErrorJson;
+
+/// The MIME type of a file that is served, from its extension.
+pub fn content_type(path: &Path) -> &'static str {
+    match path.extension().and_then(OsStr::to_str) {
+        Some("webm") => "video/webm",
+        Some("mkv") => "video/x-matroska",
+        Some("mp4" | "m4s") => "video/mp4",
+        Some("opus" | "ogg") => "audio/ogg",
+        Some("mpd") => "application/dash+xml",
+        Some("m3u8") => "application/vnd.apple.mpegurl",
+        Some("tar") => "application/x-tar",
+        _ => "application/octet-stream",
+    }
+}
+
+/// The file inside the directory at the relative path, if the path stays
+/// inside the directory.
+pub fn path_inside(
+    dir: &Path,
+    relative: &str,
+) -> Option<PathBuf> {
+    let relative = Path::new(relative);
+
+    relative
+        .components()
+        .all(|c| matches!(c, Component::Normal(_)))
+        .then(|| dir.join(relative))
+}
+
+/// Stream the file as the response, as an attachment to be saved if
+/// `attachment` is set.
+pub async fn file_response(
+    path: &Path,
+    attachment: bool,
+) -> Response {
+    let file = match tokio::fs::File::open(path).await {
+        Ok(file) => file,
+        Err(_e) => {
+            return HttpErrorJson::not_found(format!(
+                "No such file \"{}\"",
+                path.file_name().unwrap_or_default().to_string_lossy()
+            ))
+        },
+    };
+
+    let length = match file.metadata().await {
+        Ok(metadata) if metadata.is_file() => metadata.len(),
+        _ => {
+            return HttpErrorJson::not_found(format!(
+                "No such file \"{}\"",
+                path.file_name().unwrap_or_default().to_string_lossy()
+            ))
+        },
+    };
+
+    let mut response = Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, content_type(path))
+        .header(header::CONTENT_LENGTH, length);
+
+    if attachment {
+        response = response.header(
+            header::CONTENT_DISPOSITION,
+            format!(
+                "attachment; filename=\"{}\"",
+                path.file_name().unwrap_or_default().to_string_lossy()
+            ),
+        );
+    }
+
+    response
+        .body(boxed(StreamBody::new(ReaderStream::new(file))))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::path_inside;
+
+    #[test]
+    fn path_inside_keeps_relative_paths() {
+        let dir = Path::new("/jobs/1");
+
+        assert_eq!(
+            path_inside(dir, "hls/720p.m3u8"),
+            Some(dir.join("hls/720p.m3u8"))
+        );
+    }
+
+    #[test]
+    fn path_inside_rejects_parent_directories() {
+        let dir = Path::new("/jobs/1");
+
+        assert_eq!(path_inside(dir, ".."), None);
+        assert_eq!(path_inside(dir, "../2/output.webm"), None);
+        assert_eq!(path_inside(dir, "hls/../../2/output.webm"), None);
+    }
+
+    #[test]
+    fn path_inside_rejects_absolute_paths() {
+        let dir = Path::new("/jobs/1");
+
+        assert_eq!(path_inside(dir, "/etc/passwd"), None);
+        assert_eq!(path_inside(dir, "/jobs/1/output.webm"), None);
+    }
+}
@@ -1,4 +1,5 @@
 use axum::{
+    extract::multipart::MultipartError,
     http::StatusCode,
     response::{
         IntoResponse as _,
@@ -35,6 +36,24 @@ impl HttpErrorJson {
         Self::new_response(StatusCode::UNPROCESSABLE_ENTITY, message)
     }
 
+    /// The response for a multipart upload that could not be read, which is
+    /// either too large or malformed.
+    pub fn multipart_error(
+        index: usize,
+        error: &MultipartError,
+    ) -> Response {
+        if error.status() == StatusCode::PAYLOAD_TOO_LARGE {
+            let message = format!(
+                "Upload too large while reading file at index {}",
+                index
+            );
+            Self::new_response(StatusCode::PAYLOAD_TOO_LARGE, message)
+        }
+        else {
+            Self::bad_multipart(index)
+        }
+    }
+
     pub fn unimplemented(extra_message: Option<&str>) -> Response {
         let mut message = "Process not yet implemented".to_owned();
         if let Some(ex_message) = extra_message {
@@ -50,6 +69,14 @@ impl HttpErrorJson {
         Self::new_response(StatusCode::BAD_REQUEST, message)
     }
 
+    pub fn not_found(message: String) -> Response {
+        Self::new_response(StatusCode::NOT_FOUND, message)
+    }
+
+    pub fn conflict(message: String) -> Response {
+        Self::new_response(StatusCode::CONFLICT, message)
+    }
+
     pub fn internal_server_error(extra_message: Option<&str>) -> Response {
         let mut message = "Internal server error".to_owned();
         if let Some(ex_message) = extra_message {
@@ -1,8 +1,10 @@
 use std::{
     collections::HashMap,
-    ffi::OsString,
+    path::PathBuf,
+    time::Duration,
 };
 
+use futures::FutureExt as _;
 use tokio::{
     select,
     sync::{
@@ -20,21 +22,38 @@ use tokio::{
 };
 
 use crate::{
-    converter::JobStatus,
+    converter::{
+        JobEnvironment,
+        JobSettings,
+        JobStatus,
+    },
+    layout::OutputLayout,
     overseer::Job,
+    probe::ProbedInput,
 };
 
 pub enum ResponseFromAppToServer {
-    Acknowledged,
+    /// The job has been queued with the ID.
+    Created(usize),
     Deleted,
-    Status(JobStatus),
+    Status(Box<JobStatus>),
+    /// The output of the job, which is a directory for a ladder.
+    Output(PathBuf),
     NoSuchJob(usize),
     DeleteRequestIgnored(usize),
+    /// The job has no output yet.
+    NotFinished(usize),
+    /// The job has no extra target with the name.
+    NoSuchTarget(usize, String),
+    /// The job has failed, for the reason.
+    Failed(usize, String),
 }
 
 pub enum MessageFromServerToApp {
-    NewJob(OsString),
+    NewJob(Vec<ProbedInput>, OutputLayout, JobSettings, JobEnvironment),
     StatusRequest(usize),
+    /// The main output of the job, or that of the named target.
+    OutputRequest(usize, Option<String>),
     DeleteJob(usize, bool), // id, force
 }
 
@@ -64,15 +83,24 @@ pub struct AppState {
         OneshotSender<ResponseFromAppToServer>,
     )>,
     jobs: HashMap<usize, Job>,
+    /// The maximum number of jobs converting at the same time.
+    concurrency: usize,
+    /// How long finished jobs are kept before they are deleted.
+    retention: Duration,
 }
 
 impl AppState {
-    pub fn new() -> (AppState, AppStateMessenger) {
+    pub fn new(
+        concurrency: usize,
+        retention: Duration,
+    ) -> (AppState, AppStateMessenger) {
         let (sender, receiver) = unbounded();
 
         let state = AppState {
             jobs: HashMap::new(),
             requests_to_app: receiver,
+            concurrency,
+            retention,
         };
 
         let messenger = AppStateMessenger {
@@ -100,22 +128,42 @@ impl AppState {
 
         match message {
             StatusRequest(job_id) => {
-                match self.jobs.get_mut(&job_id) {
-                    None => drop(rsvp.send(NoSuchJob(job_id))),
-                    Some(job) => {
-                        let job_status = job.request_job_status().await;
-                        drop(rsvp.send(Status(job_status)));
-                    },
-                }
+                let response = match self.jobs.get(&job_id) {
+                    None => NoSuchJob(job_id),
+                    Some(job) => Status(Box::new(job.status())),
+                };
+
+                drop(rsvp.send(response));
             },
 
-            NewJob(path) => {
-                let new_job = Job::new(path);
+            NewJob(inputs, layout, settings, env) => {
+                let new_job = Job::new(inputs, layout, settings, env);
                 let new_id = self.get_new_job_id();
 
                 self.jobs.insert(new_id, new_job);
 
-                rsvp.send(Acknowledged);
+                drop(rsvp.send(Created(new_id)));
+            },
+
+            OutputRequest(job_id, target) => {
+                let outputs = self.jobs.get(&job_id).map(Job::outputs);
+
+                let response = match (outputs, target) {
+                    (None, _) => NoSuchJob(job_id),
+                    (Some(None), _) => NotFinished(job_id),
+                    (Some(Some(Err(e))), _) => Failed(job_id, e.as_error_msg()),
+                    (Some(Some(Ok(outputs))), None) => {
+                        Output(outputs.main.clone())
+                    },
+                    (Some(Some(Ok(outputs))), Some(target)) => {
+                        match outputs.targets.get(&target) {
+                            Some(output) => Output(output.clone()),
+                            None => NoSuchTarget(job_id, target),
+                        }
+                    },
+                };
+
+                drop(rsvp.send(response));
             },
 
             DeleteJob(id, force) => {
@@ -128,6 +176,7 @@ impl AppState {
                 };
 
                 if job.is_finished() || force {
+                    remove_job_dir(&job).await;
                     rsvp.send(Deleted);
                 }
                 else {
@@ -138,17 +187,52 @@ impl AppState {
         }
     }
 
-    pub async fn async_loop(&mut self) {
-        let mut all_has_finished = false;
+    /// Delete the finished jobs that have been kept for longer than the
+    /// retention period, along with their files.
+    async fn remove_expired_jobs(&mut self) {
+        let expired = self
+            .jobs
+            .iter()
+            .filter(|(_, job)| {
+                job.finished_at()
+                    .map(|t| t.elapsed() >= self.retention)
+                    .unwrap_or(false)
+            })
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
 
+        for id in expired {
+            if let Some(job) = self.jobs.remove(&id) {
+                remove_job_dir(&job).await;
+            }
+        }
+    }
+
+    pub async fn async_loop(&mut self) {
         loop {
-            let joinable = self
+            self.remove_expired_jobs().await;
+
+            // only the earliest queued jobs are run, up to the concurrency
+            let mut running = self
                 .jobs
-                .iter_mut()
-                .map(|(_, v)| v)
+                .values_mut()
                 .filter(|job| !job.is_finished())
-                .map(|job| job.run_until_finished());
-            let joined_check = futures::future::join_all(joinable);
+                .collect::<Vec<_>>();
+            running.sort_by_key(|job| job.queued_at());
+            running.truncate(self.concurrency);
+
+            let any_finished = async move {
+                if running.is_empty() {
+                    futures::future::pending::<()>().await;
+                }
+
+                futures::future::select_all(
+                    running
+                        .into_iter()
+                        .map(|job| job.run_until_finished().boxed()),
+                )
+                .await;
+            };
 
             select! {
                 maybe_message = self.requests_to_app.recv() => {
@@ -160,11 +244,22 @@ impl AppState {
                     self.process_message(message, rsvp).await;
                 },
 
-                // only check all of the jobs if not all of them are finished
-                _ = joined_check, if !all_has_finished => {
-                    all_has_finished = true;
-                },
+                // once a job finishes, the next queued job can start
+                _ = any_finished => {},
+
+                // wake up to delete the expired jobs even if nothing happens
+                _ = tokio::time::sleep(Duration::from_secs(60)) => {},
             }
         }
     }
 }
+
+async fn remove_job_dir(job: &Job) {
+    if let Err(e) = tokio::fs::remove_dir_all(job.dir()).await {
+        eprintln!(
+            "Unable to remove job directory {}: {}",
+            job.dir().display(),
+            e
+        );
+    }
+}
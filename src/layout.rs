@@ -0,0 +1,855 @@
+use core::fmt::Write;
+use std::collections::{
+    BTreeMap,
+    HashMap,
+};
+
+use serde::Serialize;
+
+use crate::probe::ProbedInput;
+
+/// Where an output audio track comes from.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+pub struct AudioSource {
+    /// The index of the uploaded file.
+    pub file: usize,
+    /// The index of the audio stream within the file.
+    pub stream: usize,
+}
+
+/// Where an output subtitle track comes from.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+pub struct SubtitleSource {
+    /// The index of the uploaded file.
+    pub file: usize,
+    /// The index of the subtitle stream within the file.
+    pub stream: usize,
+}
+
+/// The layout of the output: which file the video comes from, which audio
+/// streams the audio tracks are made of and which subtitle streams are kept.
+///
+/// Each audio track is a list of sources that are to be concatenated together,
+/// as per `audio_[n]=[m]:[c]+[m]:[c]+...`. Each subtitle track has a single
+/// source, as per `subtitle_[n]=[m]:[s]`.
+#[derive(Debug, Clone)]
+pub struct OutputLayout {
+    /// The index of the file the video comes from, if any.
+    pub video: Option<usize>,
+    pub audio: Vec<Vec<AudioSource>>,
+    pub subtitles: Vec<SubtitleSource>,
+    /// How the layout has been arrived at.
+    pub case: LayoutCase,
+}
+
+/// The cases of the 2023-05-17 ADR that a layout can come from.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LayoutCase {
+    /// Case 0.1: one video file with one audio stream.
+    SingleAudio,
+    /// Case 0.2: one video file without audio streams.
+    NoAudio,
+    /// Case 0.3: one video file with multiple audio streams.
+    MultipleAudio,
+    /// Case 0.5: one video file without audio streams and one audio file.
+    ExternalAudio,
+    /// Not in the ADR: one audio file without any video.
+    AudioOnly,
+    /// Not in the ADR: several audio files without any video.
+    AudioFiles,
+    /// Case 1: one video file and an audio file added to its audio streams.
+    AddedAudio,
+    /// Case 2: one video file without audio streams and several audio files.
+    AddedAudios,
+    /// Case 3: multiple video files and multiple audio streams.
+    Concatenation,
+    /// The layout has been given through `audio_[n]` parameters.
+    Explicit,
+}
+
+impl LayoutCase {
+    pub fn description(self) -> &'static str {
+        use LayoutCase::*;
+
+        match self {
+            SingleAudio => "case 0.1 (video with a single audio stream)",
+            NoAudio => "case 0.2 (video with no audio streams)",
+            MultipleAudio => "case 0.3 (video with multiple audio streams)",
+            ExternalAudio => {
+                "case 0.5 (video with no audio streams and one audio file)"
+            },
+            AudioOnly => "audio only (one file without video)",
+            AudioFiles => "audio only (several files without video)",
+            AddedAudio => "case 1 (adding an audio file to a video)",
+            AddedAudios => "case 2 (adding several audio files to a video)",
+            Concatenation => "case 3 (concatenating videos and audio streams)",
+            Explicit => "explicit audio_[n] parameters",
+        }
+    }
+}
+
+impl OutputLayout {
+    /// The layout requested through the `audio_[n]` query string parameters.
+    ///
+    /// The video comes from the file that has one, if any. The parameters are
+    /// checked against the uploaded files: the output track indices have to
+    /// start from zero without gaps, and every source has to refer to an
+    /// existing audio stream of an existing file.
+    pub fn from_audio_map(
+        audio_map: &HashMap<usize, Vec<(usize, usize)>>,
+        inputs: &[ProbedInput],
+    ) -> Result<OutputLayout, LayoutError> {
+        let audio_map = audio_map.iter().collect::<BTreeMap<_, _>>();
+
+        // since the keys are sorted, the first key that is not equal to its
+        // position is where the gap is
+        let gap = audio_map.keys().enumerate().find(|(idx, &&n)| *idx != n);
+        if let Some((missing, &&next)) = gap {
+            return Err(LayoutError::TrackGap { missing, next });
+        }
+
+        let audio = audio_map
+            .into_iter()
+            .map(|(&track, sources)| {
+                sources
+                    .iter()
+                    .map(|&(file, stream)| {
+                        let source = AudioSource { file, stream };
+                        Self::validate_source(track, source, inputs)?;
+                        Ok(source)
+                    })
+                    .collect()
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(OutputLayout {
+            video: Self::video_file(inputs),
+            audio,
+            subtitles: Self::every_subtitle(inputs),
+            case: LayoutCase::Explicit,
+        })
+    }
+
+    /// Keep only the subtitle streams requested through the `subtitle_[n]`
+    /// query string parameters, in their order.
+    ///
+    /// The parameters are checked against the uploaded files the same way as
+    /// the `audio_[n]` parameters are.
+    pub fn select_subtitles(
+        &mut self,
+        subtitle_map: &HashMap<usize, (usize, usize)>,
+        inputs: &[ProbedInput],
+    ) -> Result<(), LayoutError> {
+        let subtitle_map = subtitle_map.iter().collect::<BTreeMap<_, _>>();
+
+        let gap = subtitle_map.keys().enumerate().find(|(idx, &&n)| *idx != n);
+        if let Some((missing, &&next)) = gap {
+            return Err(LayoutError::SubtitleTrackGap { missing, next });
+        }
+
+        self.subtitles = subtitle_map
+            .into_iter()
+            .map(|(&track, &(file, stream))| {
+                let input = inputs.get(file).ok_or(
+                    LayoutError::NoSuchSubtitleFile {
+                        track,
+                        file,
+                        file_count: inputs.len(),
+                    },
+                )?;
+
+                let stream_count = input.subtitle_streams.len();
+                if stream >= stream_count {
+                    return Err(LayoutError::NoSuchSubtitleStream {
+                        track,
+                        file,
+                        stream,
+                        stream_count,
+                    });
+                }
+
+                Ok(SubtitleSource { file, stream })
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(())
+    }
+
+    /// Check that the source refers to an existing audio stream of an existing
+    /// file.
+    fn validate_source(
+        track: usize,
+        source: AudioSource,
+        inputs: &[ProbedInput],
+    ) -> Result<(), LayoutError> {
+        let input = inputs.get(source.file).ok_or(LayoutError::NoSuchFile {
+            track,
+            file: source.file,
+            file_count: inputs.len(),
+        })?;
+
+        if input.audio_streams.is_empty() {
+            return Err(LayoutError::FileWithoutAudio {
+                track,
+                file: source.file,
+            });
+        }
+
+        if source.stream >= input.audio_streams.len() {
+            return Err(LayoutError::NoSuchStream {
+                track,
+                file: source.file,
+                stream: source.stream,
+                stream_count: input.audio_streams.len(),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Infer the layout when no `audio_[n]` parameters are given, following
+    /// the default cases of the ADR from the stream counts of the files.
+    ///
+    /// For all other cases (case 0.6), the closest case is returned with the
+    /// query string that would have to be sent for it. Case 0.4 is recognized
+    /// but rejected, since the videos cannot be concatenated for the moment.
+    ///
+    /// Files with only subtitles, like uploaded `.srt` files, are left out of
+    /// the cases as long as they come after every other file. Every subtitle
+    /// stream of every file is kept.
+    pub fn infer(inputs: &[ProbedInput]) -> Result<OutputLayout, LayoutError> {
+        use LayoutCase::*;
+
+        let subtitles = Self::every_subtitle(inputs);
+        let subtitle_files = inputs
+            .iter()
+            .rev()
+            .take_while(|i| i.is_subtitles_only())
+            .count();
+        let inputs = &inputs[.. inputs.len() - subtitle_files];
+
+        let videos = inputs.iter().filter(|i| i.has_video()).count();
+        let audio_counts = inputs
+            .iter()
+            .map(|i| i.audio_streams.len())
+            .collect::<Vec<_>>();
+
+        let case = match (inputs.len(), videos, audio_counts.as_slice()) {
+            (1, 1, [1]) => SingleAudio,
+            (1, 1, [0]) => NoAudio,
+            (1, 1, [_]) => MultipleAudio,
+            (1, 0, [c]) if *c > 0 => AudioOnly,
+
+            // every file is a video with the same number of audio streams
+            (n, v, [c, ..])
+                if n == v && audio_counts.iter().all(|a| a == c) =>
+            {
+                return Err(LayoutError::MultipleVideos(v));
+            },
+
+            (2, 1, [0, c]) if inputs[0].has_video() && *c > 0 => ExternalAudio,
+
+            _ => return Err(Self::closest_case(inputs)),
+        };
+
+        let audio = match case {
+            ExternalAudio => Self::every_stream(&inputs[1 ..], 1),
+
+            _ => Self::every_stream(inputs, 0),
+        };
+
+        Ok(OutputLayout {
+            video: Self::video_file(inputs),
+            audio,
+            subtitles,
+            case,
+        })
+    }
+
+    /// Every subtitle stream of the files as its own track, in order.
+    fn every_subtitle(inputs: &[ProbedInput]) -> Vec<SubtitleSource> {
+        inputs
+            .iter()
+            .enumerate()
+            .flat_map(|(file, input)| {
+                (0 .. input.subtitle_streams.len())
+                    .map(move |stream| SubtitleSource { file, stream })
+            })
+            .collect()
+    }
+
+    /// Every audio stream of the files as its own track, in order. The files
+    /// are numbered starting from `first_file`.
+    fn every_stream(
+        inputs: &[ProbedInput],
+        first_file: usize,
+    ) -> Vec<Vec<AudioSource>> {
+        inputs
+            .iter()
+            .enumerate()
+            .flat_map(|(idx, input)| {
+                (0 .. input.audio_streams.len()).map(move |stream| {
+                    vec![AudioSource {
+                        file: first_file + idx,
+                        stream,
+                    }]
+                })
+            })
+            .collect()
+    }
+
+    /// Determine the case closest to the files that have been uploaded, for
+    /// when none of the default cases apply.
+    fn closest_case(inputs: &[ProbedInput]) -> LayoutError {
+        let video = Self::video_file(inputs);
+        let videos = inputs.iter().filter(|i| i.has_video()).count();
+        let video_has_audio = video
+            .map(|video| !inputs[video].audio_streams.is_empty())
+            .unwrap_or(false);
+
+        let closest = match (videos, video_has_audio) {
+            (0, _) if inputs.iter().all(|i| i.audio_streams.is_empty()) => {
+                return LayoutError::NoStreams;
+            },
+            (0, _) => LayoutCase::AudioFiles,
+            (1, true) => LayoutCase::AddedAudio,
+            (1, false) => LayoutCase::AddedAudios,
+            (_, _) => LayoutCase::Concatenation,
+        };
+
+        // keep every stream of every file, which can be trimmed down by the
+        // user as needed
+        let suggestion = OutputLayout {
+            video,
+            audio: Self::every_stream(inputs, 0),
+            subtitles: vec![],
+            case: LayoutCase::Explicit,
+        };
+
+        LayoutError::Ambiguous {
+            closest,
+            query_string: suggestion.to_query_string(),
+        }
+    }
+
+    /// The `audio_[n]` query string parameters that produce this layout.
+    pub fn to_query_string(&self) -> String {
+        self.audio
+            .iter()
+            .enumerate()
+            .map(|(track, sources)| {
+                let sources = sources
+                    .iter()
+                    .map(|s| format!("{}:{}", s.file, s.stream))
+                    .collect::<Vec<_>>()
+                    .join("+");
+
+                format!("audio_{}={}", track, sources)
+            })
+            .collect::<Vec<_>>()
+            .join("&")
+    }
+
+    /// The file the video comes from, wherever it is among the uploads. When
+    /// several files have a video, the layout is rejected by
+    /// [`OutputLayout::check_supported`].
+    fn video_file(inputs: &[ProbedInput]) -> Option<usize> {
+        inputs.iter().position(ProbedInput::has_video)
+    }
+
+    /// Check if the layout can be converted by the server as of now.
+    pub fn check_supported(
+        &self,
+        inputs: &[ProbedInput],
+    ) -> Result<(), LayoutError> {
+        // the videos do not matter if the output has no video
+        let videos = inputs.iter().filter(|i| i.has_video()).count();
+        if self.video.is_some() && videos > 1 {
+            return Err(LayoutError::MultipleVideos(videos));
+        }
+
+        match self.audio.iter().position(|sources| sources.len() > 1) {
+            Some(track) => Err(LayoutError::Concatenation(track)),
+            None => Ok(()),
+        }
+    }
+
+    /// Check that the audio tracks from files other than the video's are as
+    /// long as the video, give or take `tolerance` seconds, after the offsets
+    /// of their files are applied.
+    pub fn check_durations(
+        &self,
+        inputs: &[ProbedInput],
+        offsets: &HashMap<usize, f64>,
+        tolerance: f64,
+    ) -> Result<(), LayoutError> {
+        let video = match self.video {
+            Some(video) => video,
+            None => return Ok(()),
+        };
+
+        let video_duration = match inputs[video].duration {
+            Some(duration) => duration,
+            None => return Ok(()),
+        };
+
+        for (track, source) in self.audio.iter().enumerate() {
+            for source in source.iter().filter(|s| s.file != video) {
+                let duration = match inputs[source.file].duration {
+                    Some(duration) => duration,
+                    None => continue,
+                };
+                let offset = offsets.get(&source.file).copied().unwrap_or(0.);
+                let audio_duration = duration + offset;
+
+                if (audio_duration - video_duration).abs() > tolerance {
+                    return Err(LayoutError::DurationMismatch {
+                        track,
+                        file: source.file,
+                        audio_duration,
+                        video_duration,
+                        tolerance,
+                    });
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum LayoutError {
+    /// None of the uploaded files have any video or audio streams.
+    NoStreams,
+    /// None of the default cases apply to the uploaded files (case 0.6).
+    Ambiguous {
+        closest: LayoutCase,
+        query_string: String,
+    },
+
+    /// An `audio_[n]` index is missing while a later one is given.
+    TrackGap { missing: usize, next: usize },
+    /// An `audio_[n]` source refers to a file that has not been uploaded.
+    NoSuchFile {
+        track: usize,
+        file: usize,
+        file_count: usize,
+    },
+    /// An `audio_[n]` source refers to a file without any audio streams.
+    FileWithoutAudio { track: usize, file: usize },
+    /// An `audio_[n]` source refers to an audio stream the file does not have.
+    NoSuchStream {
+        track: usize,
+        file: usize,
+        stream: usize,
+        stream_count: usize,
+    },
+
+    /// A `subtitle_[n]` index is missing while a later one is given.
+    SubtitleTrackGap { missing: usize, next: usize },
+    /// A `subtitle_[n]` source refers to a file that has not been uploaded.
+    NoSuchSubtitleFile {
+        track: usize,
+        file: usize,
+        file_count: usize,
+    },
+    /// A `subtitle_[n]` source refers to a subtitle stream the file does not
+    /// have.
+    NoSuchSubtitleStream {
+        track: usize,
+        file: usize,
+        stream: usize,
+        stream_count: usize,
+    },
+
+    /// More than one of the uploaded files have a video.
+    MultipleVideos(usize),
+    /// The output audio track is a concatenation of several sources.
+    Concatenation(usize),
+
+    DurationMismatch {
+        track: usize,
+        file: usize,
+        audio_duration: f64,
+        video_duration: f64,
+        tolerance: f64,
+    },
+}
+
+impl LayoutError {
+    /// Whether the error is due to the server not being able to do it yet,
+    /// rather than the request being wrong.
+    pub fn is_unimplemented(&self) -> bool {
+        use LayoutError::*;
+
+        matches!(self, MultipleVideos(_) | Concatenation(_))
+    }
+
+    pub fn write_error_msg<W>(
+        &self,
+        writer: &mut W,
+    ) -> Result<(), std::fmt::Error>
+    where
+        W: Write,
+    {
+        use LayoutError::*;
+
+        match self {
+            NoStreams => {
+                write!(
+                    writer,
+                    "None of the uploaded files have any video or audio streams"
+                )
+            },
+            Ambiguous {
+                closest,
+                query_string,
+            } => {
+                write!(
+                    writer,
+                    "Unable to infer the output from the uploaded files. The \
+                     closest is {}; try again with the query string \"{}\", \
+                     removing the audio tracks that are not needed",
+                    closest.description(),
+                    query_string
+                )
+            },
+            TrackGap { missing, next } => {
+                write!(
+                    writer,
+                    "Audio track indices must start from 0 without gaps: \
+                     audio_{} is given but audio_{} is missing",
+                    next, missing
+                )
+            },
+            NoSuchFile {
+                track,
+                file,
+                file_count,
+            } => {
+                write!(
+                    writer,
+                    "audio_{} refers to file #{}, but only {} file(s) have \
+                     been uploaded (#0 to #{})",
+                    track,
+                    file,
+                    file_count,
+                    file_count.saturating_sub(1)
+                )
+            },
+            FileWithoutAudio { track, file } => {
+                write!(
+                    writer,
+                    "audio_{} refers to file #{}, which has no audio streams",
+                    track, file
+                )
+            },
+            NoSuchStream {
+                track,
+                file,
+                stream,
+                stream_count,
+            } => {
+                write!(
+                    writer,
+                    "audio_{} refers to audio stream {} of file #{}, but the \
+                     file only has {} audio stream(s) ({}:0 to {}:{})",
+                    track,
+                    stream,
+                    file,
+                    stream_count,
+                    file,
+                    file,
+                    stream_count - 1
+                )
+            },
+            SubtitleTrackGap { missing, next } => {
+                write!(
+                    writer,
+                    "Subtitle track indices must start from 0 without gaps: \
+                     subtitle_{} is given but subtitle_{} is missing",
+                    next, missing
+                )
+            },
+            NoSuchSubtitleFile {
+                track,
+                file,
+                file_count,
+            } => {
+                write!(
+                    writer,
+                    "subtitle_{} refers to file #{}, but only {} file(s) have \
+                     been uploaded (#0 to #{})",
+                    track,
+                    file,
+                    file_count,
+                    file_count.saturating_sub(1)
+                )
+            },
+            NoSuchSubtitleStream {
+                track,
+                file,
+                stream,
+                stream_count: 0,
+            } => {
+                write!(
+                    writer,
+                    "subtitle_{} refers to subtitle stream {} of file #{}, \
+                     which has no subtitle streams",
+                    track, stream, file
+                )
+            },
+            NoSuchSubtitleStream {
+                track,
+                file,
+                stream,
+                stream_count,
+            } => {
+                write!(
+                    writer,
+                    "subtitle_{} refers to subtitle stream {} of file #{}, but \
+                     the file only has {} subtitle stream(s) ({}:0 to {}:{})",
+                    track,
+                    stream,
+                    file,
+                    stream_count,
+                    file,
+                    file,
+                    stream_count - 1
+                )
+            },
+            MultipleVideos(count) => {
+                write!(
+                    writer,
+                    "Cannot concatenate the videos of {} files for the moment",
+                    count
+                )
+            },
+            Concatenation(track) => {
+                write!(
+                    writer,
+                    "Cannot concatenate the sources of audio track {} for the \
+                     moment",
+                    track
+                )
+            },
+            DurationMismatch {
+                track,
+                file,
+                audio_duration,
+                video_duration,
+                tolerance,
+            } => {
+                write!(
+                    writer,
+                    "Audio track {} from file #{} lasts {:.3}s with its offset \
+                     while the video lasts {:.3}s, which is more than {:.3}s \
+                     apart",
+                    track, file, audio_duration, video_duration, tolerance
+                )
+            },
+        }
+    }
+
+    pub fn as_error_msg(&self) -> String {
+        let mut retval = String::new();
+        self.write_error_msg(&mut retval).unwrap();
+        retval
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A probed file with a video stream or not and some audio streams.
+    fn input(
+        video: bool,
+        audio: usize,
+    ) -> ProbedInput {
+        let video_streams = if video {
+            vec![serde_json::from_str(r#"{"width":1920,"height":1080}"#)
+                .unwrap()]
+        }
+        else {
+            vec![]
+        };
+        let audio_streams = (0 .. audio)
+            .map(|_| {
+                serde_json::from_str(r#"{"codec_name":"opus","channels":2}"#)
+                    .unwrap()
+            })
+            .collect();
+
+        ProbedInput {
+            path: "input".into(),
+            duration: None,
+            audio_streams,
+            video_streams,
+            subtitle_streams: vec![],
+        }
+    }
+
+    fn source(
+        file: usize,
+        stream: usize,
+    ) -> AudioSource {
+        AudioSource { file, stream }
+    }
+
+    /// The `audio_[n]` parameters, as the query string gives them.
+    fn audio_map(
+        tracks: &[(usize, &[(usize, usize)])]
+    ) -> HashMap<usize, Vec<(usize, usize)>> {
+        tracks
+            .iter()
+            .map(|&(track, sources)| (track, sources.to_vec()))
+            .collect()
+    }
+
+    #[test]
+    fn from_audio_map_keeps_the_track_order() {
+        let inputs = [input(true, 1), input(false, 2)];
+        let map = audio_map(&[(1, &[(0, 0)]), (0, &[(1, 1)])]);
+
+        let layout = OutputLayout::from_audio_map(&map, &inputs).unwrap();
+        assert_eq!(layout.case, LayoutCase::Explicit);
+        assert_eq!(layout.video, Some(0));
+        assert_eq!(layout.audio, vec![vec![source(1, 1)], vec![source(0, 0)]]);
+    }
+
+    #[test]
+    fn from_audio_map_rejects_gaps() {
+        let inputs = [input(true, 2)];
+        let map = audio_map(&[(0, &[(0, 0)]), (2, &[(0, 1)])]);
+
+        let error = OutputLayout::from_audio_map(&map, &inputs).unwrap_err();
+        assert!(matches!(
+            error,
+            LayoutError::TrackGap {
+                missing: 1,
+                next: 2,
+            }
+        ));
+    }
+
+    #[test]
+    fn from_audio_map_rejects_missing_sources() {
+        let inputs = [input(true, 0), input(false, 2)];
+
+        let map = audio_map(&[(0, &[(2, 0)])]);
+        let error = OutputLayout::from_audio_map(&map, &inputs).unwrap_err();
+        assert!(matches!(
+            error,
+            LayoutError::NoSuchFile {
+                track: 0,
+                file: 2,
+                file_count: 2,
+            }
+        ));
+
+        let map = audio_map(&[(0, &[(1, 0)]), (1, &[(0, 0)])]);
+        let error = OutputLayout::from_audio_map(&map, &inputs).unwrap_err();
+        assert!(matches!(
+            error,
+            LayoutError::FileWithoutAudio { track: 1, file: 0 }
+        ));
+
+        let map = audio_map(&[(0, &[(1, 2)])]);
+        let error = OutputLayout::from_audio_map(&map, &inputs).unwrap_err();
+        assert!(matches!(
+            error,
+            LayoutError::NoSuchStream {
+                track: 0,
+                file: 1,
+                stream: 2,
+                stream_count: 2,
+            }
+        ));
+    }
+
+    #[test]
+    fn infer_single_video() {
+        let layout = OutputLayout::infer(&[input(true, 1)]).unwrap();
+        assert_eq!(layout.case, LayoutCase::SingleAudio);
+        assert_eq!(layout.video, Some(0));
+        assert_eq!(layout.audio, vec![vec![source(0, 0)]]);
+
+        let layout = OutputLayout::infer(&[input(true, 0)]).unwrap();
+        assert_eq!(layout.case, LayoutCase::NoAudio);
+        assert!(layout.audio.is_empty());
+
+        let layout = OutputLayout::infer(&[input(true, 2)]).unwrap();
+        assert_eq!(layout.case, LayoutCase::MultipleAudio);
+        assert_eq!(layout.audio, vec![vec![source(0, 0)], vec![source(0, 1)]]);
+    }
+
+    #[test]
+    fn infer_audio_only() {
+        let layout = OutputLayout::infer(&[input(false, 2)]).unwrap();
+        assert_eq!(layout.case, LayoutCase::AudioOnly);
+        assert_eq!(layout.video, None);
+        assert_eq!(layout.audio, vec![vec![source(0, 0)], vec![source(0, 1)]]);
+    }
+
+    #[test]
+    fn infer_external_audio() {
+        let layout =
+            OutputLayout::infer(&[input(true, 0), input(false, 1)]).unwrap();
+        assert_eq!(layout.case, LayoutCase::ExternalAudio);
+        assert_eq!(layout.video, Some(0));
+        assert_eq!(layout.audio, vec![vec![source(1, 0)]]);
+    }
+
+    #[test]
+    fn infer_rejects_concatenated_videos() {
+        let error =
+            OutputLayout::infer(&[input(true, 2), input(true, 2)]).unwrap_err();
+        assert!(matches!(error, LayoutError::MultipleVideos(2)));
+        assert!(error.is_unimplemented());
+    }
+
+    #[test]
+    fn infer_suggests_the_closest_case() {
+        let error = OutputLayout::infer(&[input(true, 1), input(false, 1)])
+            .unwrap_err();
+        match error {
+            LayoutError::Ambiguous {
+                closest,
+                query_string,
+            } => {
+                assert_eq!(closest, LayoutCase::AddedAudio);
+                assert_eq!(query_string, "audio_0=0:0&audio_1=1:0");
+            },
+            e => panic!("unexpected error: {:?}", e),
+        }
+
+        let error =
+            OutputLayout::infer(&[input(true, 1), input(true, 2)]).unwrap_err();
+        assert!(matches!(
+            error,
+            LayoutError::Ambiguous {
+                closest: LayoutCase::Concatenation,
+                ..
+            }
+        ));
+
+        let error = OutputLayout::infer(&[input(false, 1), input(false, 1)])
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            LayoutError::Ambiguous {
+                closest: LayoutCase::AudioFiles,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn infer_without_any_streams() {
+        let error = OutputLayout::infer(&[input(false, 0), input(false, 0)])
+            .unwrap_err();
+        assert!(matches!(error, LayoutError::NoStreams));
+    }
+}
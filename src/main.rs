@@ -1,25 +1,41 @@
+mod capabilities;
+mod config;
 mod converter;
+mod download;
 mod error_responses;
 mod job_manager;
+mod layout;
 mod overseer;
+mod presets;
+mod probe;
 mod query_string;
+mod spec;
 
 use std::{
     ffi::OsString,
-    net::SocketAddr,
+    path::{
+        Path,
+        PathBuf,
+    },
+    sync::Arc,
 };
 
 use axum::{
-    body::boxed,
     extract::{
+        DefaultBodyLimit,
+        FromRef,
         Multipart,
+        Path as UrlPath,
         State,
     },
     http::{
         StatusCode,
         Uri,
     },
-    response::Response,
+    response::{
+        IntoResponse as _,
+        Response,
+    },
     routing::{
         on,
         MethodFilter,
@@ -33,23 +49,132 @@ use tokio::{
 };
 
 use crate::{
+    capabilities::{
+        write_requirements,
+        Capabilities,
+    },
+    config::Config,
+    converter::{
+        target_video_bitrate,
+        JobEnvironment,
+    },
     error_responses::HttpErrorJson,
     job_manager::{
         AppState,
         AppStateMessenger,
         MessageFromServerToApp,
+        ResponseFromAppToServer,
     },
+    layout::OutputLayout,
+    presets::Presets,
+    probe::ProbedInput,
 };
 
+/// The state shared by the handlers of the web server.
+#[derive(Clone)]
+struct ServerState {
+    messenger: AppStateMessenger,
+    presets: Arc<Presets>,
+    config: Arc<Config>,
+    capabilities: Arc<Capabilities>,
+}
+
+impl FromRef<ServerState> for AppStateMessenger {
+    fn from_ref(state: &ServerState) -> AppStateMessenger {
+        state.messenger.clone()
+    }
+}
+
+impl FromRef<ServerState> for Arc<Presets> {
+    fn from_ref(state: &ServerState) -> Arc<Presets> {
+        state.presets.clone()
+    }
+}
+
+impl FromRef<ServerState> for Arc<Config> {
+    fn from_ref(state: &ServerState) -> Arc<Config> {
+        state.config.clone()
+    }
+}
+
+impl FromRef<ServerState> for Arc<Capabilities> {
+    fn from_ref(state: &ServerState) -> Arc<Capabilities> {
+        state.capabilities.clone()
+    }
+}
+
 #[tokio::main]
 async fn main() {
-    let (mut app_state, app_state_messenger) = AppState::new();
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e.as_error_msg());
+            std::process::exit(1);
+        },
+    };
+
+    // a missing encoder or filter would otherwise only show up partway
+    // through a job
+    let capabilities =
+        match Capabilities::probe(&config.ffmpeg, &config.ffprobe).await {
+            Ok(capabilities) => capabilities,
+            Err(e) => {
+                eprintln!("{}", e.as_error_msg());
+                std::process::exit(1);
+            },
+        };
+    if let Err(e) = capabilities.check_defaults() {
+        eprintln!("{}", e.as_error_msg());
+        std::process::exit(1);
+    }
+
+    let (mut app_state, app_state_messenger) =
+        AppState::new(config.concurrency, config.retention);
+
+    let mut presets = match &config.presets {
+        None => Presets::default(),
+        Some(path) => match Presets::load(path) {
+            Ok(presets) => presets,
+            Err(e) => {
+                eprintln!("{}", e.as_error_msg());
+                std::process::exit(1);
+            },
+        },
+    };
+
+    presets.disable_unsupported(&capabilities);
+    for (name, missing) in presets.all_disabled() {
+        let mut message = String::new();
+        write_requirements(&mut message, missing).unwrap();
+        eprintln!("Disabled preset \"{}\": missing the {}", name, message);
+    }
+
+    let addr = config.bind;
+    let max_upload_size = config.max_upload_size;
+
+    let state = ServerState {
+        messenger: app_state_messenger,
+        presets: Arc::new(presets),
+        config: Arc::new(config),
+        capabilities: Arc::new(capabilities),
+    };
 
     let router = Router::new()
         .route("/upload", on(MethodFilter::POST, on_multipart_upload))
-        .with_state(app_state_messenger);
-
-    let addr = SocketAddr::from(([127, 0, 0, 1], 8080));
+        .route("/presets", on(MethodFilter::GET, on_presets_list))
+        .route("/capabilities", on(MethodFilter::GET, on_capabilities))
+        .route("/jobs/:id", on(MethodFilter::GET, on_status))
+        .route("/jobs/:id/download", on(MethodFilter::GET, on_download))
+        .route(
+            "/jobs/:id/download/*path",
+            on(MethodFilter::GET, on_package_download),
+        )
+        .route(
+            "/jobs/:id/targets/:name/download",
+            on(MethodFilter::GET, on_target_download),
+        )
+        .layer(DefaultBodyLimit::max(max_upload_size))
+        .with_state(state);
 
     // > the StateMessenger sent into the web server is just a messenger to the
     //   actual AppState. this is held by axum.
@@ -72,35 +197,198 @@ async fn main() {
     };
 }
 
+/// Behavior for the web server when asked for the loaded presets.
+async fn on_presets_list(State(presets): State<Arc<Presets>>) -> Response {
+    axum::Json(presets.to_json()).into_response()
+}
+
+/// Behavior for the web server when asked for what FFmpeg can do, along with
+/// the presets that have been disabled because of it.
+async fn on_capabilities(
+    State(capabilities): State<Arc<Capabilities>>,
+    State(presets): State<Arc<Presets>>,
+) -> Response {
+    let mut report = serde_json::to_value(&*capabilities).unwrap();
+    report["disabled_presets"] =
+        serde_json::to_value(presets.all_disabled()).unwrap();
+
+    axum::Json(report).into_response()
+}
+
+/// Behavior for the web server when asked for the status of a job, with
+/// everything that has been decided and measured for it so far.
+async fn on_status(
+    State(state): State<AppStateMessenger>,
+    UrlPath(id): UrlPath<usize>,
+) -> Response {
+    use ResponseFromAppToServer::*;
+
+    let response = state
+        .send_message_expecting_response(MessageFromServerToApp::StatusRequest(
+            id,
+        ))
+        .await;
+
+    match response {
+        Ok(Status(status)) => axum::Json(status).into_response(),
+        Ok(NoSuchJob(id)) => {
+            HttpErrorJson::not_found(format!("No job with ID {}", id))
+        },
+        _ => HttpErrorJson::internal_server_error(None),
+    }
+}
+
+/// Ask the app for the main output of the job, or that of the named target,
+/// or the response to send if there is none.
+async fn job_output(
+    state: &AppStateMessenger,
+    id: usize,
+    target: Option<String>,
+) -> Result<PathBuf, Response> {
+    use ResponseFromAppToServer::*;
+
+    let response = state
+        .send_message_expecting_response(MessageFromServerToApp::OutputRequest(
+            id, target,
+        ))
+        .await;
+
+    match response {
+        Ok(Output(output)) => Ok(output),
+        Ok(NoSuchJob(id)) => {
+            Err(HttpErrorJson::not_found(format!("No job with ID {}", id)))
+        },
+        Ok(NotFinished(id)) => Err(HttpErrorJson::conflict(format!(
+            "Job {} has not finished yet",
+            id
+        ))),
+        Ok(NoSuchTarget(id, target)) => Err(HttpErrorJson::not_found(format!(
+            "Job {} has no target \"{}\"",
+            id, target
+        ))),
+        Ok(Failed(id, message)) => Err(HttpErrorJson::internal_server_error(
+            Some(&format!("Job {} has failed: {}", id, message)),
+        )),
+        _ => Err(HttpErrorJson::internal_server_error(None)),
+    }
+}
+
+/// Behavior for the web server when asked for the output of a job.
+///
+/// The package of a ladder is sent as one tarball.
+async fn on_download(
+    State(state): State<AppStateMessenger>,
+    UrlPath(id): UrlPath<usize>,
+) -> Response {
+    let output = match job_output(&state, id, None).await {
+        Ok(output) => output,
+        Err(response) => return response,
+    };
+
+    if output.is_dir() {
+        download::file_response(&output.with_extension("tar"), true).await
+    }
+    else {
+        download::file_response(&output, true).await
+    }
+}
+
+/// Behavior for the web server when asked for a file of the package of a
+/// ladder, like its manifest or its segments, so that players can stream it
+/// in place.
+async fn on_package_download(
+    State(state): State<AppStateMessenger>,
+    UrlPath((id, path)): UrlPath<(usize, String)>,
+) -> Response {
+    let output = match job_output(&state, id, None).await {
+        Ok(output) => output,
+        Err(response) => return response,
+    };
+
+    if !output.is_dir() {
+        return HttpErrorJson::not_found(format!(
+            "Job {} has no package, only a single output",
+            id
+        ));
+    }
+
+    match download::path_inside(&output, &path) {
+        Some(file) => download::file_response(&file, false).await,
+        None => HttpErrorJson::not_found(format!("No such file \"{}\"", path)),
+    }
+}
+
+/// Behavior for the web server when asked for the output of an extra target
+/// of a job.
+async fn on_target_download(
+    State(state): State<AppStateMessenger>,
+    UrlPath((id, name)): UrlPath<(usize, String)>,
+) -> Response {
+    match job_output(&state, id, Some(name)).await {
+        Ok(output) => download::file_response(&output, true).await,
+        Err(response) => response,
+    }
+}
+
 /// Behavior for the web server when receiving a multipart upload request.
+///
+/// The uploads are written into a new directory for the job, which is removed
+/// if the job is not accepted.
 async fn on_multipart_upload(
     state: State<AppStateMessenger>,
+    State(presets): State<Arc<Presets>>,
+    State(config): State<Arc<Config>>,
+    State(capabilities): State<Arc<Capabilities>>,
     uri: Uri,
-    mut multipart: Multipart,
+    multipart: Multipart,
 ) -> Response {
     eprintln!("Received file upload request.");
 
-    let mut files: Vec<OsString> = vec![];
+    let dir = config
+        .work_dir
+        .join(format!("{:016x}", rand::random::<u64>()));
+    if let Err(e) = tokio::fs::create_dir(&dir).await {
+        eprintln!("Unable to create job directory {}: {}", dir.display(), e);
+        return HttpErrorJson::internal_server_error(None);
+    }
+
+    let env = JobEnvironment {
+        dir: dir.clone(),
+        ffmpeg: config.ffmpeg.clone(),
+        ffprobe: config.ffprobe.clone(),
+    };
+
+    let response =
+        accept_upload(state, &presets, &capabilities, env, uri, multipart)
+            .await;
+
+    if response.status() != StatusCode::CREATED {
+        drop(tokio::fs::remove_dir_all(&dir).await);
+    }
 
-    dbg!(());
+    response
+}
+
+async fn accept_upload(
+    state: State<AppStateMessenger>,
+    presets: &Presets,
+    capabilities: &Capabilities,
+    env: JobEnvironment,
+    uri: Uri,
+    mut multipart: Multipart,
+) -> Response {
+    let mut files: Vec<PathBuf> = vec![];
+    let mut spec = None;
 
     let qsc = match query_string::get_requests(uri.query().unwrap_or("")) {
         Ok(qsc) => qsc,
         Err(e) => return HttpErrorJson::bad_request(e.as_error_msg()),
     };
 
-    dbg!(qsc);
-
-    // TODO: delete me
-    return Response::builder()
-        .status(StatusCode::CREATED)
-        .body(boxed("Ok".to_owned()))
-        .unwrap();
-
     // for every file that exists in the field
     let mut index = 0;
     while let Some((mut field, index)) = match multipart.next_field().await {
-        Err(e) => return HttpErrorJson::bad_multipart(index),
+        Err(e) => return HttpErrorJson::multipart_error(index, &e),
         Ok(None) => None,
         Ok(Some(field)) => {
             // update the file index
@@ -109,24 +397,51 @@ async fn on_multipart_upload(
             Some((field, index))
         },
     } {
-        if files.len() > 1 {
-            eprintln!(
-                "Server can't yet handle multiple media to be concatenated."
-            );
-            return HttpErrorJson::unimplemented(Some(
-                "Cannot process multiple files for the moment.",
-            ));
+        // a job spec is sent as its own part rather than as a file, and its
+        // type may come with parameters like `; charset=utf-8`
+        let is_json = field
+            .content_type()
+            .and_then(|t| t.split(';').next())
+            .map(|t| t.trim().eq_ignore_ascii_case("application/json"))
+            .unwrap_or(false);
+        if field.name() == Some("spec") || is_json {
+            if spec.is_some() {
+                return HttpErrorJson::bad_request(
+                    "More than one job spec uploaded".to_owned(),
+                );
+            }
+
+            let json = match field.text().await {
+                Ok(json) => json,
+                Err(_e) => return HttpErrorJson::bad_multipart(index),
+            };
+
+            match spec::get_spec(&json) {
+                Ok(contents) => spec = Some(contents),
+                Err(e) => return HttpErrorJson::bad_request(e.as_error_msg()),
+            }
+
+            continue;
         }
 
-        // determine the filename
-        let filename = match field.file_name() {
+        // determine the filename, keeping only its last component so that the
+        // file stays inside the directory of the job
+        let filename = match field
+            .file_name()
+            .and_then(|fname| Path::new(fname).file_name())
+        {
             None => {
                 return HttpErrorJson::bad_request(format!(
                     "No file name found for file #{}",
                     index
                 ))
             },
-            Some(fname) => fname.to_owned(),
+            // prefixed with the index in case files share a name
+            Some(fname) => {
+                let mut filename = OsString::from(format!("{}-", files.len()));
+                filename.push(fname);
+                env.dir.join(filename)
+            },
         };
 
         // create the file
@@ -142,7 +457,13 @@ async fn on_multipart_upload(
 
         // write the file
         {
-            while let Some(bytes) = field.try_next().await.unwrap() {
+            loop {
+                let bytes = match field.try_next().await {
+                    Ok(Some(bytes)) => bytes,
+                    Ok(None) => break,
+                    Err(e) => return HttpErrorJson::multipart_error(index, &e),
+                };
+
                 // TODO: an appropriate error
                 file.write(&bytes).await.unwrap();
             }
@@ -158,19 +479,156 @@ async fn on_multipart_upload(
             // downloaded is a valid file
         }
 
-        eprintln!("Written {}", filename);
+        eprintln!("Written {}", filename.display());
+
+        files.push(filename);
+    }
+
+    // query string keys take precedence over the spec, which takes precedence
+    // over the preset
+    let qsc = match spec {
+        Some(spec) => qsc.overriding(spec),
+        None => qsc,
+    };
+    let preset_name = qsc.preset.clone();
+    let qsc = match preset_name.as_deref() {
+        None => qsc,
+        Some(name) => {
+            if let Some(missing) = presets.disabled(name) {
+                let mut message = format!(
+                    "Preset \"{}\" is disabled since FFmpeg is missing the ",
+                    name
+                );
+                write_requirements(&mut message, missing).unwrap();
+                return HttpErrorJson::unimplemented(Some(&message));
+            }
+
+            match presets.get(name) {
+                Some(preset) => qsc.overriding(preset.clone()),
+                None => {
+                    return HttpErrorJson::bad_request(format!(
+                        "Unknown preset \"{}\"",
+                        name
+                    ))
+                },
+            }
+        },
+    };
+
+    if files.is_empty() {
+        return HttpErrorJson::bad_request("No files uploaded".to_owned());
+    }
+
+    let inputs = futures::future::join_all(
+        files
+            .into_iter()
+            .map(|f| ProbedInput::probe(&env.ffprobe, f)),
+    )
+    .await;
+
+    let settings = qsc.job_settings();
+    let mut layout = if qsc.audio_map().is_empty() {
+        match OutputLayout::infer(&inputs) {
+            Ok(layout) => layout,
+            Err(e) if e.is_unimplemented() => {
+                return HttpErrorJson::unimplemented(Some(&e.as_error_msg()))
+            },
+            Err(e) => return HttpErrorJson::bad_request(e.as_error_msg()),
+        }
+    }
+    else {
+        match OutputLayout::from_audio_map(qsc.audio_map(), &inputs) {
+            Ok(layout) => layout,
+            Err(e) => return HttpErrorJson::bad_request(e.as_error_msg()),
+        }
+    };
+
+    if !qsc.subtitle_map().is_empty() {
+        if let Err(e) = layout.select_subtitles(qsc.subtitle_map(), &inputs) {
+            return HttpErrorJson::bad_request(e.as_error_msg());
+        }
+    }
 
-        files.push(filename.into());
+    if !settings.keep_subtitles {
+        layout.subtitles.clear();
     }
 
-    // TODO: soon, you'll be able to take more files
-    let file = files.into_iter().next().unwrap();
-    state
+    if let Some(source) = settings
+        .metadata
+        .source
+        .filter(|&source| source >= inputs.len())
+    {
+        return HttpErrorJson::bad_request(format!(
+            "The metadata is taken from file #{}, but only {} file(s) have \
+             been uploaded (#0 to #{})",
+            source,
+            inputs.len(),
+            inputs.len().saturating_sub(1)
+        ));
+    }
+
+    if settings.video.disabled {
+        if layout.audio.is_empty() {
+            return HttpErrorJson::bad_request(
+                "Audio only output requested, but there is no audio".to_owned(),
+            );
+        }
+
+        layout.video = None;
+    }
+
+    let missing = capabilities.missing_for(&settings, layout.video.is_some());
+    if !missing.is_empty() {
+        let mut message = "FFmpeg is missing the ".to_owned();
+        write_requirements(&mut message, &missing).unwrap();
+        return HttpErrorJson::unimplemented(Some(&message));
+    }
+
+    let layout_check = layout.check_supported(&inputs).and_then(|_| {
+        layout.check_durations(
+            &inputs,
+            &settings.audio.input_offsets,
+            settings.audio.duration_tolerance,
+        )
+    });
+    match layout_check {
+        Err(e) if e.is_unimplemented() => {
+            return HttpErrorJson::unimplemented(Some(&e.as_error_msg()))
+        },
+        Err(e) => return HttpErrorJson::bad_request(e.as_error_msg()),
+        Ok(()) => {},
+    }
+
+    for video in settings.all_video() {
+        if let Err(e) = video.check_container() {
+            return HttpErrorJson::bad_request(e.as_error_msg());
+        }
+
+        if let Err(e) = video.check_burn_in(&inputs) {
+            return HttpErrorJson::bad_request(e.as_error_msg());
+        }
+
+        if let Err(e) =
+            target_video_bitrate(&inputs, &layout, &settings.audio, video)
+        {
+            return HttpErrorJson::bad_request(e.as_error_msg());
+        }
+    }
+
+    let response = state
         .0
-        .send_message_expecting_response(MessageFromServerToApp::NewJob(file));
+        .send_message_expecting_response(MessageFromServerToApp::NewJob(
+            inputs, layout, settings, env,
+        ))
+        .await;
 
-    Response::builder()
-        .status(StatusCode::CREATED)
-        .body(boxed("Ok".to_owned()))
-        .unwrap()
+    match response {
+        // the ID is sent as a string since it does not fit in a double
+        Ok(ResponseFromAppToServer::Created(id)) => (
+            StatusCode::CREATED,
+            axum::Json(serde_json::json!({ "id": id.to_string() })),
+        )
+            .into_response(),
+        _ => HttpErrorJson::internal_server_error(None),
+    }
 }
@@ -1,26 +1,44 @@
 use std::{
-    ffi::OsString,
-    path::PathBuf,
-    sync::Arc,
+    path::{
+        Path,
+        PathBuf,
+    },
+    sync::{
+        Arc,
+        Mutex,
+    },
+    time::Instant,
 };
 
 use futures::{
     future::BoxFuture,
     FutureExt as _,
 };
-use tokio::sync::mpsc::{
-    unbounded_channel,
-    UnboundedReceiver,
-    UnboundedSender,
+use serde::Serialize;
+
+use crate::{
+    converter::{
+        AudioConstants,
+        AudioGain,
+        AudioTrackDecision,
+        AudioTrackPlan,
+        CrfChoice,
+        JobEnvironment,
+        JobError,
+        JobOutputs,
+        JobSettings,
+        JobStatus,
+        SubtitleTrackPlan,
+        VmafSample,
+        VmafTrial,
+    },
+    layout::OutputLayout,
+    probe::{
+        FileMetadata,
+        ProbedInput,
+    },
 };
 
-use crate::converter::{
-    AudioConstants,
-    JobStatus,
-};
-
-pub struct RequestForJobStatus;
-
 pub enum JobToOverseerMessage {
     // finished progresses
     //AudioFirstPassFinished,
@@ -28,63 +46,146 @@ pub enum JobToOverseerMessage {
     VideoFirstPassFinished,
     VideoSecondPassFinished,
 
+    /// The input has no video, so only the audio is converted.
+    NoVideoStream,
+    /// The input has no audio, so only the video is converted.
+    NoAudioStream,
+    /// The cover art of the input is left out, as the container cannot carry
+    /// it.
+    CoverArtDropped,
+
+    AudioTracksPlanned(Arc<[AudioTrackPlan]>),
     AudioConstantsDetermined(Arc<[AudioConstants]>), /* aka AudioFirstPassFinished */
+    AudioDecisionsDetermined(Arc<[AudioTrackDecision]>),
+    AudioGainsDetermined(Arc<[Option<AudioGain>]>),
+    /// The subtitle tracks of an output and whether they are kept in it.
+    SubtitlesPlanned(Arc<[SubtitleTrackPlan]>),
     VideoDimensionsDetermined(usize, usize),
-    VideoCrfDetermined(usize),
+    VideoCrfDetermined(CrfChoice),
+    /// The samples that the CRFs are tried on in the VMAF mode.
+    VideoVmafSamplesChosen(Arc<[VmafSample]>),
+    VideoVmafTrialFinished(VmafTrial),
+    /// The bitrate that the video is encoded at instead of a CRF, to fit the
+    /// target size.
+    VideoBitrateDetermined(u64),
 
     VideoSecondPassProgress(PathBuf),
 
     VideoConversionFinished,
+    /// The number of renditions of the ladder.
+    VideoRenditionsPlanned(usize),
+    /// An update about a rendition of the ladder.
+    Rendition(usize, Box<JobToOverseerMessage>),
+    /// The DASH manifest of the ladder and its HLS master playlist, if it has
+    /// one.
+    PackageWritten(PathBuf, Option<PathBuf>),
+    /// The size of the muxed output, and whether it fits in the target size
+    /// if there is one.
+    OutputSizeChecked(u64, Option<bool>),
+    /// The chapters, global tags and attachments of the muxed output.
+    OutputMetadataProbed(FileMetadata),
+    /// An update about an extra target.
+    Target(usize, Box<JobToOverseerMessage>),
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum AudioVideoStatus {
     FirstPass,
     SecondPass,
     Finished,
+    /// The input has no such stream to convert.
+    NotApplicable,
 }
 
 pub struct Job {
-    future: BoxFuture<'static, PathBuf>,
-    status_receiver: UnboundedReceiver<JobStatus>,
-    request_sender: UnboundedSender<RequestForJobStatus>,
-    output: Option<PathBuf>,
+    future: BoxFuture<'static, Result<JobOutputs, JobError>>,
+    /// The status of the job, which the job updates as it goes.
+    status: Arc<Mutex<JobStatus>>,
+    /// The outputs of the job once it has finished, or why it has failed.
+    outputs: Option<Result<JobOutputs, JobError>>,
+    /// The directory of the files of the job.
+    dir: PathBuf,
+    queued_at: Instant,
+    finished_at: Option<Instant>,
 }
 
 impl Job {
-    pub fn new(path: OsString) -> Job {
-        let (status_sender, status_receiver) = unbounded_channel();
-        let (request_sender, request_receiver) = unbounded_channel();
-
+    pub fn new(
+        inputs: Vec<ProbedInput>,
+        layout: OutputLayout,
+        settings: JobSettings,
+        env: JobEnvironment,
+    ) -> Job {
+        let status = Arc::new(Mutex::new(JobStatus::new(
+            layout.case,
+            &settings.targets,
+        )));
+
+        let dir = env.dir.clone();
         let future = crate::converter::actually_run_job(
-            path,
-            status_sender,
-            request_receiver,
+            inputs,
+            layout,
+            settings,
+            env,
+            status.clone(),
         )
         .boxed();
 
         Job {
             future,
-            status_receiver,
-            request_sender,
-            output: None,
+            status,
+            outputs: None,
+            dir,
+            queued_at: Instant::now(),
+            finished_at: None,
         }
     }
 
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn queued_at(&self) -> Instant {
+        self.queued_at
+    }
+
+    /// When the job has finished, if it has.
+    pub fn finished_at(&self) -> Option<Instant> {
+        self.finished_at
+    }
+
+    /// The outputs of the job, or why it has failed, if it has finished.
+    pub fn outputs(&self) -> Option<&Result<JobOutputs, JobError>> {
+        self.outputs.as_ref()
+    }
+
     pub fn is_finished(&self) -> bool {
-        self.output.is_some()
+        self.outputs.is_some()
     }
 
     pub async fn run_until_finished(&mut self) {
-        if self.output.is_some() {
+        if self.outputs.is_some() {
             return;
         }
 
-        self.output = Some((&mut self.future).await);
+        let outputs = (&mut self.future).await;
+        if let Err(e) = &outputs {
+            eprintln!(
+                "Job in {} failed: {}",
+                self.dir.display(),
+                e.as_error_msg()
+            );
+        }
+
+        self.status.lock().unwrap().finish(outputs.as_ref().err());
+        self.outputs = Some(outputs);
+        self.finished_at = Some(Instant::now());
     }
 
-    pub async fn request_job_status(&mut self) -> JobStatus {
-        self.request_sender.send(RequestForJobStatus);
-        self.status_receiver.recv().await.unwrap()
+    /// The status of the job so far, which can be asked for at any time,
+    /// even before the job has started or after it has finished.
+    pub fn status(&self) -> JobStatus {
+        self.status.lock().unwrap().clone()
     }
 }
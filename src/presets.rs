@@ -0,0 +1,183 @@
+use core::fmt::Write;
+use std::{
+    collections::BTreeMap,
+    path::{
+        Path,
+        PathBuf,
+    },
+};
+
+use crate::{
+    capabilities::{
+        Capabilities,
+        Requirement,
+    },
+    query_string::QueryStringContents,
+    spec::{
+        get_spec_value,
+        SpecError,
+    },
+};
+
+/// The named job specs that clients can select with `preset=name`.
+///
+/// Presets are read from a JSON object whose keys are the names of the
+/// presets and whose values are job specs.
+#[derive(Debug, Default)]
+pub struct Presets {
+    presets: BTreeMap<String, Preset>,
+    /// The presets that FFmpeg cannot run, with what they are missing.
+    disabled: BTreeMap<String, Vec<Requirement>>,
+}
+
+#[derive(Debug)]
+struct Preset {
+    /// The spec as written in the presets file, for listing.
+    spec: serde_json::Value,
+    contents: QueryStringContents,
+}
+
+#[derive(Debug)]
+pub enum PresetError {
+    Read(PathBuf, std::io::Error),
+    Json(PathBuf, serde_json::Error),
+    Spec(String, SpecError),
+    /// A preset that selects another preset.
+    Nested(String),
+}
+
+impl PresetError {
+    pub fn write_error_msg<W>(
+        &self,
+        writer: &mut W,
+    ) -> Result<(), std::fmt::Error>
+    where
+        W: Write,
+    {
+        use PresetError::*;
+
+        match self {
+            Read(path, e) => {
+                write!(
+                    writer,
+                    "Unable to read presets file \"{}\": {}",
+                    path.display(),
+                    e
+                )
+            },
+            Json(path, e) => {
+                write!(
+                    writer,
+                    "Unable to parse presets file \"{}\": expected an object \
+                     of job specs: {}",
+                    path.display(),
+                    e
+                )
+            },
+            Spec(name, e) => {
+                write!(writer, "Invalid preset \"{}\": ", name)?;
+                e.write_error_msg(writer)
+            },
+            Nested(name) => {
+                write!(
+                    writer,
+                    "Invalid preset \"{}\": presets cannot select other \
+                     presets",
+                    name
+                )
+            },
+        }
+    }
+
+    pub fn as_error_msg(&self) -> String {
+        let mut retval = String::new();
+        self.write_error_msg(&mut retval).unwrap();
+        retval
+    }
+}
+
+impl Presets {
+    /// Read the presets from a file, failing on the first invalid preset.
+    pub fn load(path: impl AsRef<Path>) -> Result<Presets, PresetError> {
+        let path = path.as_ref();
+
+        let json = std::fs::read_to_string(path)
+            .map_err(|e| PresetError::Read(path.to_owned(), e))?;
+        let specs: BTreeMap<String, serde_json::Value> =
+            serde_json::from_str(&json)
+                .map_err(|e| PresetError::Json(path.to_owned(), e))?;
+
+        let mut presets = BTreeMap::new();
+
+        for (name, spec) in specs {
+            let contents = match get_spec_value(spec.clone()) {
+                Ok(contents) => contents,
+                Err(e) => return Err(PresetError::Spec(name, e)),
+            };
+
+            if contents.preset.is_some() {
+                return Err(PresetError::Nested(name));
+            }
+
+            presets.insert(name, Preset { spec, contents });
+        }
+
+        Ok(Presets {
+            presets,
+            disabled: BTreeMap::new(),
+        })
+    }
+
+    /// Disable the presets that need something that FFmpeg does not have.
+    pub fn disable_unsupported(
+        &mut self,
+        capabilities: &Capabilities,
+    ) {
+        for (name, preset) in self.presets.iter() {
+            let settings = preset.contents.job_settings();
+
+            // without audio only, the inputs decide whether there is a video
+            let mut missing = capabilities.missing_for(&settings, false);
+            if !settings.video.disabled {
+                missing.extend(capabilities.missing_for(&settings, true));
+            }
+            missing.sort();
+            missing.dedup();
+
+            if !missing.is_empty() {
+                self.disabled.insert(name.clone(), missing);
+            }
+        }
+
+        let disabled = &self.disabled;
+        self.presets.retain(|name, _| !disabled.contains_key(name));
+    }
+
+    /// What a disabled preset is missing, if the preset is disabled.
+    pub fn disabled(
+        &self,
+        name: &str,
+    ) -> Option<&[Requirement]> {
+        self.disabled.get(name).map(Vec::as_slice)
+    }
+
+    pub fn all_disabled(&self) -> &BTreeMap<String, Vec<Requirement>> {
+        &self.disabled
+    }
+
+    pub(crate) fn get(
+        &self,
+        name: &str,
+    ) -> Option<&QueryStringContents> {
+        self.presets.get(name).map(|p| &p.contents)
+    }
+
+    /// The loaded presets as a JSON object of their names and specs.
+    pub fn to_json(&self) -> serde_json::Value {
+        self.presets
+            .iter()
+            .map(|(name, preset)| (name.clone(), preset.spec.clone()))
+            .collect::<serde_json::Map<_, _>>()
+            .into()
+    }
+}
@@ -0,0 +1,515 @@
+use std::{
+    collections::BTreeMap,
+    ffi::OsStr,
+    path::{
+        Path,
+        PathBuf,
+    },
+};
+
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use tokio::{
+    join,
+    process::Command,
+};
+
+/// Information of an audio stream, as read by FFprobe.
+#[derive(Debug, Clone, Deserialize)]
+pub struct AudioStreamInfo {
+    pub codec_name: String,
+    pub channels: usize,
+    #[serde(default)]
+    pub channel_layout: Option<String>,
+    /// The bitrate in bits per second, which FFprobe writes as a string.
+    #[serde(default)]
+    bit_rate: Option<String>,
+    #[serde(default)]
+    tags: AudioTags,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+struct AudioTags {
+    /// The bitrate that Matroska muxers write, since the container has none.
+    #[serde(default, rename = "BPS")]
+    bps: Option<String>,
+}
+
+impl AudioStreamInfo {
+    /// The bitrate of the stream in bits per second, if it is known.
+    pub fn bitrate(&self) -> Option<u64> {
+        self.bit_rate
+            .as_deref()
+            .or(self.tags.bps.as_deref())
+            .and_then(|b| b.parse::<u64>().ok())
+            .filter(|&b| b > 0)
+    }
+}
+
+/// Information of a video stream, as read by FFprobe.
+#[derive(Debug, Clone, Deserialize)]
+pub struct VideoStreamInfo {
+    pub width: usize,
+    pub height: usize,
+    /// The codec of the stream, like `h264` or `mjpeg`.
+    #[serde(default)]
+    codec_name: Option<String>,
+    /// The average frame rate, as a fraction like `30000/1001`.
+    #[serde(default)]
+    avg_frame_rate: Option<String>,
+    /// The shape of the pixels, as a ratio like `4:3`.
+    #[serde(default)]
+    sample_aspect_ratio: Option<String>,
+    #[serde(default)]
+    disposition: VideoDisposition,
+    /// The display matrix, which phones write instead of the `rotate` tag.
+    #[serde(default)]
+    side_data_list: Vec<VideoSideData>,
+    #[serde(default)]
+    tags: VideoTags,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct VideoSideData {
+    #[serde(default)]
+    rotation: Option<f64>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+struct VideoTags {
+    /// The rotation of older files, in degrees.
+    #[serde(default)]
+    rotate: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+struct VideoDisposition {
+    /// Whether the stream is a cover art rather than an actual video.
+    #[serde(default)]
+    attached_pic: u8,
+}
+
+/// Information of a subtitle stream, as read by FFprobe.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SubtitleStreamInfo {
+    pub codec_name: String,
+}
+
+/// The subtitle codecs that are made of images rather than text.
+const IMAGE_SUBTITLE_CODECS: [&str; 4] =
+    ["hdmv_pgs_subtitle", "dvd_subtitle", "dvb_subtitle", "xsub"];
+
+impl SubtitleStreamInfo {
+    /// Whether the subtitles are images, like PGS or VobSub, which cannot be
+    /// converted into a text format.
+    pub fn is_image(&self) -> bool {
+        IMAGE_SUBTITLE_CODECS.contains(&self.codec_name.as_str())
+    }
+}
+
+impl VideoStreamInfo {
+    pub fn is_cover_art(&self) -> bool {
+        self.disposition.attached_pic != 0
+    }
+
+    /// The MIME type of the stream if it is a JPEG or PNG picture, which
+    /// players take as is.
+    pub fn picture_mime_type(&self) -> Option<&'static str> {
+        match self.codec_name.as_deref()? {
+            "mjpeg" => Some("image/jpeg"),
+            "png" => Some("image/png"),
+            _ => None,
+        }
+    }
+
+    /// The average frame rate, in frames per second, if it is known.
+    pub fn frame_rate(&self) -> Option<f64> {
+        let (num, den) = self.avg_frame_rate.as_deref()?.split_once('/')?;
+        let (num, den) = (num.parse::<f64>().ok()?, den.parse::<f64>().ok()?);
+
+        // FFprobe writes `0/0` when it does not know the frame rate
+        (num > 0. && den > 0.).then(|| num / den)
+    }
+
+    /// The rotation of the video in degrees, as one of 0, 90, 180 and 270.
+    pub fn rotation(&self) -> u16 {
+        let rotation = self
+            .side_data_list
+            .iter()
+            .find_map(|d| d.rotation)
+            .or_else(|| self.tags.rotate.as_deref()?.parse::<f64>().ok())
+            .unwrap_or(0.);
+
+        ((rotation / 90.).round() as i64).rem_euclid(4) as u16 * 90
+    }
+
+    /// The width and height of the video as it is displayed, which is after
+    /// stretching the pixels into squares and rotating it. FFmpeg does both
+    /// before the video gets into the filters.
+    pub fn display_dimensions(&self) -> (usize, usize) {
+        let sar = self
+            .sample_aspect_ratio
+            .as_deref()
+            .and_then(|r| r.split_once(':'))
+            .and_then(|(n, d)| {
+                Some((n.parse::<f64>().ok()?, d.parse::<f64>().ok()?))
+            })
+            // FFprobe writes `0:1` when it does not know the ratio
+            .filter(|&(n, d)| n > 0. && d > 0.)
+            .map_or(1., |(n, d)| n / d);
+        let width = (self.width as f64 * sar).round() as usize;
+
+        if self.rotation() % 180 == 90 {
+            (self.height, width)
+        }
+        else {
+            (width, self.height)
+        }
+    }
+}
+
+/// The chapters, global tags and attachments of a file, as read by FFprobe.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct FileMetadata {
+    /// The global tags, like `title`, with their keys as FFprobe writes them.
+    pub tags: BTreeMap<String, String>,
+    pub chapters: Vec<ChapterInfo>,
+    /// The file names of the attachments, like fonts.
+    pub attachments: Vec<String>,
+}
+
+/// A chapter of a file, with its times in seconds.
+#[derive(Debug, Clone, Serialize)]
+pub struct ChapterInfo {
+    pub start: f64,
+    pub end: f64,
+    pub title: Option<String>,
+}
+
+/// An uploaded file and what FFprobe has read from it.
+#[derive(Debug, Clone)]
+pub struct ProbedInput {
+    pub path: PathBuf,
+    /// The duration of the file, in seconds.
+    pub duration: Option<f64>,
+    pub audio_streams: Vec<AudioStreamInfo>,
+    /// The video streams of the file, including cover arts.
+    pub video_streams: Vec<VideoStreamInfo>,
+    pub subtitle_streams: Vec<SubtitleStreamInfo>,
+}
+
+impl ProbedInput {
+    /// Probe the file at `path` with the FFprobe binary at `ffprobe`.
+    pub async fn probe(
+        ffprobe: &Path,
+        path: PathBuf,
+    ) -> ProbedInput {
+        let (duration, audio_streams, video_streams, subtitle_streams) = join!(
+            determine_duration(ffprobe, &path),
+            determine_audio_streams(ffprobe, &path),
+            determine_video_streams(ffprobe, &path),
+            determine_subtitle_streams(ffprobe, &path),
+        );
+
+        ProbedInput {
+            path,
+            duration,
+            audio_streams,
+            video_streams,
+            subtitle_streams,
+        }
+    }
+
+    /// Whether the file has a video stream that is not a cover art.
+    pub fn has_video(&self) -> bool {
+        self.video_streams.iter().any(|s| !s.is_cover_art())
+    }
+
+    /// The first video stream that is not a cover art.
+    pub fn video_stream(&self) -> Option<&VideoStreamInfo> {
+        self.video_streams.iter().find(|s| !s.is_cover_art())
+    }
+
+    /// The index of the first cover art among the video streams of the file.
+    pub fn cover_art(&self) -> Option<usize> {
+        self.video_streams.iter().position(|s| s.is_cover_art())
+    }
+
+    /// Whether the file only has subtitles, like an uploaded `.srt` file.
+    pub fn is_subtitles_only(&self) -> bool {
+        self.audio_streams.is_empty()
+            && self.video_streams.is_empty()
+            && !self.subtitle_streams.is_empty()
+    }
+}
+
+/// Use FFprobe to read the duration of a file, in seconds.
+async fn determine_duration(
+    ffprobe: &Path,
+    path: impl AsRef<OsStr>,
+) -> Option<f64> {
+    let command = Command::new(ffprobe)
+        .arg("-hide_banner")
+        .arg("-v")
+        .arg("error")
+        .arg("-show_entries")
+        .arg("format=duration")
+        .arg("-print_format")
+        .arg("json")
+        .arg(path)
+        .output()
+        .await
+        .unwrap();
+
+    #[derive(Deserialize)]
+    struct Format {
+        // FFprobe writes the duration as a string
+        duration: String,
+    }
+
+    #[derive(Deserialize)]
+    struct Entries {
+        format: Format,
+    }
+
+    let format = String::from_utf8(command.stdout).unwrap();
+    serde_json::from_str::<Entries>(&format)
+        .ok()
+        .and_then(|e| e.format.duration.parse::<f64>().ok())
+}
+
+/// Use FFprobe to read the codecs and channel layouts of the audio tracks of a
+/// file.
+///
+/// The number of elements in the vec is the same as the number of audio tracks
+/// in a container.
+pub async fn determine_audio_streams(
+    ffprobe: &Path,
+    path: impl AsRef<OsStr>,
+) -> Vec<AudioStreamInfo> {
+    let command = Command::new(ffprobe)
+        .arg("-hide_banner")
+        .arg("-v")
+        .arg("error")
+        .arg("-select_streams")
+        .arg("a")
+        .arg("-show_entries")
+        .arg("stream=codec_name,channels,channel_layout,bit_rate:stream_tags=BPS")
+        .arg("-print_format")
+        .arg("json")
+        .arg(path)
+        .output()
+        .await
+        .unwrap();
+
+    #[derive(Deserialize)]
+    struct Entries {
+        streams: Vec<AudioStreamInfo>,
+    }
+
+    let streams = String::from_utf8(command.stdout).unwrap();
+    serde_json::from_str::<Entries>(&streams)
+        .map(|e| e.streams)
+        .unwrap_or_default()
+}
+
+/// Use FFprobe to read the video streams of a file, including cover arts.
+pub async fn determine_video_streams(
+    ffprobe: &Path,
+    path: impl AsRef<OsStr>,
+) -> Vec<VideoStreamInfo> {
+    let command = Command::new(ffprobe)
+        .arg("-hide_banner")
+        .arg("-v")
+        .arg("error")
+        .arg("-select_streams")
+        .arg("v")
+        .arg("-show_entries")
+        .arg(
+            "stream=width,height,codec_name,avg_frame_rate,\
+             sample_aspect_ratio:\
+             stream_disposition=attached_pic:stream_side_data=rotation:\
+             stream_tags=rotate",
+        )
+        .arg("-print_format")
+        .arg("json")
+        .arg(path)
+        .output()
+        .await
+        .unwrap();
+
+    #[derive(Deserialize)]
+    struct Entries {
+        // programs:
+        streams: Vec<VideoStreamInfo>,
+    }
+
+    let streams = String::from_utf8(command.stdout).unwrap();
+    serde_json::from_str::<Entries>(&streams)
+        .map(|e| e.streams)
+        .unwrap_or_default()
+}
+
+/// Use FFprobe to read the codecs of the subtitle streams of a file.
+pub async fn determine_subtitle_streams(
+    ffprobe: &Path,
+    path: impl AsRef<OsStr>,
+) -> Vec<SubtitleStreamInfo> {
+    let command = Command::new(ffprobe)
+        .arg("-hide_banner")
+        .arg("-v")
+        .arg("error")
+        .arg("-select_streams")
+        .arg("s")
+        .arg("-show_entries")
+        .arg("stream=codec_name")
+        .arg("-print_format")
+        .arg("json")
+        .arg(path)
+        .output()
+        .await
+        .unwrap();
+
+    #[derive(Deserialize)]
+    struct Entries {
+        streams: Vec<SubtitleStreamInfo>,
+    }
+
+    let streams = String::from_utf8(command.stdout).unwrap();
+    serde_json::from_str::<Entries>(&streams)
+        .map(|e| e.streams)
+        .unwrap_or_default()
+}
+
+/// Use FFprobe to read the chapters, global tags and attachments of a file.
+pub async fn determine_metadata(
+    ffprobe: &Path,
+    path: impl AsRef<OsStr>,
+) -> FileMetadata {
+    let command = Command::new(ffprobe)
+        .arg("-hide_banner")
+        .arg("-v")
+        .arg("error")
+        .arg("-select_streams")
+        .arg("t")
+        .arg("-show_entries")
+        .arg(
+            "format_tags:chapter=start_time,end_time:chapter_tags=title:\
+             stream_tags=filename",
+        )
+        .arg("-print_format")
+        .arg("json")
+        .arg(path)
+        .output()
+        .await
+        .unwrap();
+
+    #[derive(Default, Deserialize)]
+    struct Tags {
+        #[serde(default)]
+        title: Option<String>,
+        #[serde(default)]
+        filename: Option<String>,
+    }
+
+    #[derive(Deserialize)]
+    struct Chapter {
+        // FFprobe writes the times as strings
+        start_time: String,
+        end_time: String,
+        #[serde(default)]
+        tags: Tags,
+    }
+
+    #[derive(Deserialize)]
+    struct Attachment {
+        #[serde(default)]
+        tags: Tags,
+    }
+
+    #[derive(Default, Deserialize)]
+    struct Format {
+        #[serde(default)]
+        tags: BTreeMap<String, String>,
+    }
+
+    #[derive(Deserialize)]
+    struct Entries {
+        #[serde(default)]
+        format: Format,
+        #[serde(default)]
+        chapters: Vec<Chapter>,
+        #[serde(default)]
+        streams: Vec<Attachment>,
+    }
+
+    let entries = String::from_utf8(command.stdout).unwrap();
+    let entries = match serde_json::from_str::<Entries>(&entries) {
+        Ok(entries) => entries,
+        Err(_) => return FileMetadata::default(),
+    };
+
+    FileMetadata {
+        tags: entries.format.tags,
+        chapters: entries
+            .chapters
+            .into_iter()
+            .map(|c| ChapterInfo {
+                start: c.start_time.parse().unwrap_or(0.),
+                end: c.end_time.parse().unwrap_or(0.),
+                title: c.tags.title,
+            })
+            .collect(),
+        attachments: entries
+            .streams
+            .into_iter()
+            .filter_map(|s| s.tags.filename)
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::VideoStreamInfo;
+
+    fn stream(json: &str) -> VideoStreamInfo {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn display_dimensions_swap_at_quarter_turns() {
+        let rotated = stream(
+            r#"{"width": 1920, "height": 1080,
+                "side_data_list": [{"rotation": -90}]}"#,
+        );
+        assert_eq!(rotated.rotation(), 270);
+        assert_eq!(rotated.display_dimensions(), (1080, 1920));
+
+        let tagged = stream(
+            r#"{"width": 1920, "height": 1080, "tags": {"rotate": "90"}}"#,
+        );
+        assert_eq!(tagged.rotation(), 90);
+        assert_eq!(tagged.display_dimensions(), (1080, 1920));
+
+        let upside_down = stream(
+            r#"{"width": 1920, "height": 1080,
+                "side_data_list": [{"rotation": 180}]}"#,
+        );
+        assert_eq!(upside_down.display_dimensions(), (1920, 1080));
+    }
+
+    #[test]
+    fn display_dimensions_stretch_non_square_pixels() {
+        let anamorphic = stream(
+            r#"{"width": 1440, "height": 1080, "sample_aspect_ratio": "4:3"}"#,
+        );
+        assert_eq!(anamorphic.display_dimensions(), (1920, 1080));
+
+        let unknown = stream(
+            r#"{"width": 1920, "height": 1080, "sample_aspect_ratio": "0:1"}"#,
+        );
+        assert_eq!(unknown.display_dimensions(), (1920, 1080));
+    }
+}
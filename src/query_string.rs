@@ -1,9 +1,35 @@
 use core::fmt::Write;
 use std::{
-    collections::HashMap,
+    collections::{
+        BTreeMap,
+        HashMap,
+    },
     num::IntErrorKind,
 };
 
+use percent_encoding::percent_decode_str;
+
+use crate::{
+    config::parse_size,
+    converter::{
+        AudioCodec,
+        AudioOnlyContainer,
+        AudioTrackOptions,
+        Container,
+        CrfMode,
+        JobSettings,
+        LoudnessMode,
+        OpusApplication,
+        OpusSettings,
+        OpusVbr,
+        OutputTarget,
+        Scaler,
+        SegmentFormat,
+        VideoCodec,
+    },
+    layout::SubtitleSource,
+};
+
 #[derive(Debug, Clone)]
 pub enum QueryStringErrorSource<'a> {
     AudioKey(&'a str, IntErrorKind),
@@ -11,6 +37,61 @@ pub enum QueryStringErrorSource<'a> {
 
     AudioFileSource(&'a str, IntErrorKind),
     AudioChannelSource(&'a str, IntErrorKind),
+
+    LoudnessTolerance(&'a str),
+    LoudnessMode(&'a str),
+    LoudnessTarget(&'a str),
+
+    AudioBitrate(&'a str),
+    AudioVbr(&'a str),
+    AudioApplication(&'a str),
+    AudioDownmix(&'a str),
+    StereoDownmix(&'a str),
+
+    AudioLanguage(&'a str),
+    AudioTitle(&'a str),
+    AudioDefault(&'a str),
+    AudioCodec(&'a str),
+
+    AudioOnlyContainer(&'a str),
+    AudioOnly(&'a str),
+
+    SubtitleKey(&'a str, IntErrorKind),
+    SubtitleSource(&'a str),
+    Subtitles(&'a str),
+
+    MetadataSource(&'a str, IntErrorKind),
+    TagName(&'a str),
+    TagValue(&'a str),
+
+    VideoCodec(&'a str),
+    VideoCpuUsed(&'a str),
+    VideoMaxHeight(&'a str),
+    VideoMaxWidth(&'a str),
+    VideoTargetHeight(&'a str),
+    VideoScaler(&'a str),
+    VideoCrfMode(&'a str),
+    VideoCrfBand(&'a str),
+    VideoVmafTarget(&'a str),
+    VideoTargetSize(&'a str),
+    VideoMaxBitrate(&'a str),
+    VideoBufferSize(&'a str),
+    VideoBufferSizeWithoutMaxBitrate,
+    VideoKeyframeInterval(&'a str),
+    VideoSceneCut(&'a str),
+    VideoLadder(&'a str),
+    VideoSegments(&'a str),
+    VideoContainer(&'a str),
+
+    TargetName(&'a str),
+    TargetCodec(&'a str),
+
+    OffsetKey(&'a str, IntErrorKind),
+    Offset(&'a str),
+    DurationTolerance(&'a str),
+
+    Strict(&'a str),
+    UnknownKeys(Vec<&'a str>),
 }
 
 // taken directly from core::num::error.rs
@@ -69,6 +150,380 @@ impl<'a> QueryStringErrorSource<'a> {
                      exist"
                 )
             },
+            LoudnessTolerance(s) => {
+                write!(
+                    writer,
+                    "Unable to parse loudness tolerance \"{}\" from query \
+                     string: expected a non-negative number",
+                    s
+                )
+            },
+            LoudnessMode(s) => {
+                write!(
+                    writer,
+                    "Unable to parse loudness mode \"{}\" from query string: \
+                     expected one of \"normalize\", \"r128\" or \
+                     \"replaygain\"",
+                    s
+                )
+            },
+            LoudnessTarget(s) => {
+                write!(
+                    writer,
+                    "Unable to parse loudness target \"{}\" from query \
+                     string: expected LUFS from -70 to -5",
+                    s
+                )
+            },
+            AudioBitrate(s) => {
+                write!(
+                    writer,
+                    "Unable to parse audio bitrate \"{}\" from query string: \
+                     expected bits per second of at least 500, optionally \
+                     suffixed with \"k\"",
+                    s
+                )
+            },
+            AudioVbr(s) => {
+                write!(
+                    writer,
+                    "Unable to parse audio VBR mode \"{}\" from query string: \
+                     expected one of \"on\", \"off\" or \"constrained\"",
+                    s
+                )
+            },
+            AudioApplication(s) => {
+                write!(
+                    writer,
+                    "Unable to parse audio application \"{}\" from query \
+                     string: expected one of \"audio\", \"voip\" or \
+                     \"lowdelay\"",
+                    s
+                )
+            },
+            AudioDownmix(s) => {
+                write!(
+                    writer,
+                    "Unable to parse audio downmix \"{}\" from query string: \
+                     expected \"true\" or \"false\"",
+                    s
+                )
+            },
+            StereoDownmix(s) => {
+                write!(
+                    writer,
+                    "Unable to parse stereo downmix \"{}\" from query \
+                     string: expected \"true\" or \"false\"",
+                    s
+                )
+            },
+            AudioLanguage(s) => {
+                write!(
+                    writer,
+                    "Unable to parse audio language \"{}\" from query string: \
+                     expected an ISO 639-2 code or a BCP 47 tag",
+                    s
+                )
+            },
+            AudioTitle(s) => {
+                write!(
+                    writer,
+                    "Unable to parse audio title \"{}\" from query string: \
+                     expected percent-encoded UTF-8",
+                    s
+                )
+            },
+            AudioDefault(s) => {
+                write!(
+                    writer,
+                    "Unable to parse audio default \"{}\" from query string: \
+                     expected \"true\" or \"false\"",
+                    s
+                )
+            },
+            AudioCodec(s) => {
+                write!(
+                    writer,
+                    "Unable to parse audio codec \"{}\" from query string: \
+                     expected one of \"auto\", \"opus\" or \"copy\"",
+                    s
+                )
+            },
+            AudioOnlyContainer(s) => {
+                write!(
+                    writer,
+                    "Unable to parse audio-only container \"{}\" from query \
+                     string: expected one of \"opus\", \"ogg\" or \"webm\"",
+                    s
+                )
+            },
+            AudioOnly(s) => {
+                write!(
+                    writer,
+                    "Unable to parse audio only \"{}\" from query string: \
+                     expected \"true\" or \"false\"",
+                    s
+                )
+            },
+            SubtitleKey(s, kind) => {
+                write!(
+                    writer,
+                    "Unable to parse subtitle key \"{}\" from query string: \
+                     {}",
+                    s,
+                    iek_description(*kind)
+                )
+            },
+            SubtitleSource(s) => {
+                write!(
+                    writer,
+                    "Unable to parse subtitle source \"{}\" from query \
+                     string: expected a file and a subtitle stream like \
+                     \"0:1\"",
+                    s
+                )
+            },
+            Subtitles(s) => {
+                write!(
+                    writer,
+                    "Unable to parse subtitles \"{}\" from query string: \
+                     expected \"true\" or \"false\"",
+                    s
+                )
+            },
+            MetadataSource(s, kind) => {
+                write!(
+                    writer,
+                    "Unable to parse metadata source \"{}\" from query \
+                     string: {}",
+                    s,
+                    iek_description(*kind)
+                )
+            },
+            TagName(s) => {
+                write!(
+                    writer,
+                    "Unable to parse tag name \"{}\" from query string: \
+                     expected up to 64 letters, digits or underscores",
+                    s
+                )
+            },
+            TagValue(s) => {
+                write!(
+                    writer,
+                    "Unable to parse tag value \"{}\" from query string: \
+                     expected percent-encoded UTF-8",
+                    s
+                )
+            },
+            VideoCodec(s) => {
+                write!(
+                    writer,
+                    "Unable to parse video codec \"{}\" from query string: \
+                     expected \"av1\", \"vp9\" or \"h264\"",
+                    s
+                )
+            },
+            VideoCpuUsed(s) => {
+                write!(
+                    writer,
+                    "Unable to parse video CPU used \"{}\" from query \
+                     string: expected an integer from 0 to 8",
+                    s
+                )
+            },
+            VideoMaxHeight(s) => {
+                write!(
+                    writer,
+                    "Unable to parse video maximum height \"{}\" from query \
+                     string: expected a positive even integer",
+                    s
+                )
+            },
+            VideoMaxWidth(s) => {
+                write!(
+                    writer,
+                    "Unable to parse video maximum width \"{}\" from query \
+                     string: expected a positive even integer",
+                    s
+                )
+            },
+            VideoTargetHeight(s) => {
+                write!(
+                    writer,
+                    "Unable to parse video target height \"{}\" from query \
+                     string: expected a positive even integer",
+                    s
+                )
+            },
+            VideoScaler(s) => {
+                write!(
+                    writer,
+                    "Unable to parse video scaler \"{}\" from query string: \
+                     expected \"bilinear\", \"bicubic\", \"lanczos\", \
+                     \"spline\" or \"area\"",
+                    s
+                )
+            },
+            VideoCrfMode(s) => {
+                write!(
+                    writer,
+                    "Unable to parse video CRF mode \"{}\" from query \
+                     string: expected \"resolution\", \"content\" or \"vmaf\"",
+                    s
+                )
+            },
+            VideoCrfBand(s) => {
+                write!(
+                    writer,
+                    "Unable to parse video CRF band \"{}\" from query \
+                     string: expected an integer from 0 to 63",
+                    s
+                )
+            },
+            VideoVmafTarget(s) => {
+                write!(
+                    writer,
+                    "Unable to parse video VMAF target \"{}\" from query \
+                     string: expected a number above 0 and up to 100",
+                    s
+                )
+            },
+            VideoTargetSize(s) => {
+                write!(
+                    writer,
+                    "Unable to parse video target size \"{}\" from query \
+                     string: expected a positive number of bytes optionally \
+                     suffixed with \"K\", \"M\" or \"G\"",
+                    s
+                )
+            },
+            VideoMaxBitrate(s) => {
+                write!(
+                    writer,
+                    "Unable to parse video maximum bitrate \"{}\" from query \
+                     string: expected a positive number of bits per second \
+                     optionally suffixed with \"k\" or \"M\"",
+                    s
+                )
+            },
+            VideoBufferSize(s) => {
+                write!(
+                    writer,
+                    "Unable to parse video buffer size \"{}\" from query \
+                     string: expected a positive number of bits optionally \
+                     suffixed with \"k\" or \"M\"",
+                    s
+                )
+            },
+            VideoBufferSizeWithoutMaxBitrate => {
+                write!(
+                    writer,
+                    "video_buffer_size is only used with video_max_bitrate, \
+                     which is not given"
+                )
+            },
+            VideoKeyframeInterval(s) => {
+                write!(
+                    writer,
+                    "Unable to parse video keyframe interval \"{}\" from \
+                     query string: expected a positive number of seconds",
+                    s
+                )
+            },
+            VideoSceneCut(s) => {
+                write!(
+                    writer,
+                    "Unable to parse video scene cut \"{}\" from query \
+                     string: expected \"true\" or \"false\"",
+                    s
+                )
+            },
+            VideoLadder(s) => {
+                write!(
+                    writer,
+                    "Unable to parse video ladder \"{}\" from query string: \
+                     expected positive even heights joined by \"+\"",
+                    s
+                )
+            },
+            VideoSegments(s) => {
+                write!(
+                    writer,
+                    "Unable to parse video segments \"{}\" from query \
+                     string: expected \"mp4\" or \"webm\"",
+                    s
+                )
+            },
+            VideoContainer(s) => {
+                write!(
+                    writer,
+                    "Unable to parse video container \"{}\" from query \
+                     string: expected \"webm\", \"mkv\" or \"mp4\"",
+                    s
+                )
+            },
+            TargetName(s) => {
+                write!(
+                    writer,
+                    "Unable to parse target name \"{}\" from query string: \
+                     expected up to 32 letters, digits or dashes",
+                    s
+                )
+            },
+            TargetCodec(s) => {
+                write!(
+                    writer,
+                    "Unable to parse target codec \"{}\" from query string: \
+                     expected \"av1\", \"vp9\" or \"h264\"",
+                    s
+                )
+            },
+            OffsetKey(s, kind) => {
+                write!(
+                    writer,
+                    "Unable to parse offset key \"{}\" from query string: {}",
+                    s,
+                    iek_description(*kind)
+                )
+            },
+            Offset(s) => {
+                write!(
+                    writer,
+                    "Unable to parse offset \"{}\" from query string: \
+                     expected a number of seconds",
+                    s
+                )
+            },
+            DurationTolerance(s) => {
+                write!(
+                    writer,
+                    "Unable to parse duration tolerance \"{}\" from query \
+                     string: expected a non-negative number of seconds",
+                    s
+                )
+            },
+            Strict(s) => {
+                write!(
+                    writer,
+                    "Unable to parse strict mode \"{}\" from query string: \
+                     expected \"true\" or \"false\"",
+                    s
+                )
+            },
+            UnknownKeys(keys) => {
+                write!(writer, "Unrecognized query string keys: ")?;
+
+                for (idx, key) in keys.iter().enumerate() {
+                    if idx > 0 {
+                        write!(writer, ", ")?;
+                    }
+
+                    write!(writer, "\"{}\"", key)?;
+                }
+
+                Ok(())
+            },
         }
     }
 
@@ -79,36 +534,1035 @@ impl<'a> QueryStringErrorSource<'a> {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub(crate) struct QueryStringContents {
-    audio_map: HashMap<usize, Vec<(usize, usize)>>,
+    pub(crate) audio_map: HashMap<usize, Vec<(usize, usize)>>,
+    pub(crate) loudness_tolerance: Option<f64>,
+    pub(crate) loudness_mode: Option<LoudnessMode>,
+    pub(crate) audio_opus: OpusSettings,
+    pub(crate) track_opus: HashMap<usize, OpusSettings>,
+    pub(crate) track_options: HashMap<usize, AudioTrackOptions>,
+    pub(crate) stereo_downmix: Option<bool>,
+    pub(crate) audio_only_container: Option<AudioOnlyContainer>,
+    pub(crate) input_offsets: HashMap<usize, f64>,
+    pub(crate) duration_tolerance: Option<f64>,
+    pub(crate) loudness_target: Option<f64>,
+    pub(crate) audio_only: Option<bool>,
+    pub(crate) subtitle_map: HashMap<usize, (usize, usize)>,
+    pub(crate) subtitles: Option<bool>,
+    pub(crate) metadata_source: Option<usize>,
+    pub(crate) tags: BTreeMap<String, String>,
+    pub(crate) video_codec: Option<VideoCodec>,
+    pub(crate) video_cpu_used: Option<u8>,
+    pub(crate) video_max_height: Option<usize>,
+    pub(crate) video_max_width: Option<usize>,
+    pub(crate) video_target_height: Option<usize>,
+    pub(crate) video_scaler: Option<Scaler>,
+    pub(crate) video_crf_mode: Option<CrfMode>,
+    pub(crate) video_crf_band: Option<usize>,
+    pub(crate) video_vmaf_target: Option<f64>,
+    pub(crate) video_target_size: Option<usize>,
+    pub(crate) video_max_bitrate: Option<u64>,
+    pub(crate) video_buffer_size: Option<u64>,
+    pub(crate) video_keyframe_interval: Option<f64>,
+    pub(crate) video_scene_cut: Option<bool>,
+    pub(crate) video_ladder: Option<Vec<usize>>,
+    pub(crate) video_segments: Option<SegmentFormat>,
+    pub(crate) video_container: Option<Container>,
+    pub(crate) video_burn_subtitles: Option<(usize, usize)>,
+    /// The extra targets by their names, each with only the video settings
+    /// that differ from those of the main output.
+    pub(crate) targets: BTreeMap<String, QueryStringContents>,
+    /// The name of the preset that the rest of the contents override.
+    pub(crate) preset: Option<String>,
 }
 
+impl QueryStringContents {
+    pub(crate) fn audio_map(&self) -> &HashMap<usize, Vec<(usize, usize)>> {
+        &self.audio_map
+    }
+
+    pub(crate) fn subtitle_map(&self) -> &HashMap<usize, (usize, usize)> {
+        &self.subtitle_map
+    }
+
+    /// Combine these contents with those of a job spec, with everything given
+    /// here taking precedence over the spec.
+    pub(crate) fn overriding(
+        self,
+        spec: QueryStringContents,
+    ) -> QueryStringContents {
+        let mut audio_map = spec.audio_map;
+        audio_map.extend(self.audio_map);
+
+        let mut track_opus = spec.track_opus;
+        for (track, opus) in self.track_opus {
+            let merged = match track_opus.get(&track) {
+                Some(spec_opus) => opus.or(spec_opus),
+                None => opus,
+            };
+            track_opus.insert(track, merged);
+        }
+
+        let mut track_options = spec.track_options;
+        for (track, options) in self.track_options {
+            let merged = match track_options.get(&track) {
+                Some(spec_options) => options.or(spec_options),
+                None => options,
+            };
+            track_options.insert(track, merged);
+        }
+
+        let mut input_offsets = spec.input_offsets;
+        input_offsets.extend(self.input_offsets);
+
+        let mut subtitle_map = spec.subtitle_map;
+        subtitle_map.extend(self.subtitle_map);
+
+        let mut tags = spec.tags;
+        tags.extend(self.tags);
+
+        let mut targets = spec.targets;
+        for (name, target) in self.targets {
+            let merged = match targets.remove(&name) {
+                Some(spec_target) => target.overriding(spec_target),
+                None => target,
+            };
+            targets.insert(name, merged);
+        }
+
+        QueryStringContents {
+            audio_map,
+            loudness_tolerance: self
+                .loudness_tolerance
+                .or(spec.loudness_tolerance),
+            loudness_mode: self.loudness_mode.or(spec.loudness_mode),
+            audio_opus: self.audio_opus.or(&spec.audio_opus),
+            track_opus,
+            track_options,
+            stereo_downmix: self.stereo_downmix.or(spec.stereo_downmix),
+            audio_only_container: self
+                .audio_only_container
+                .or(spec.audio_only_container),
+            input_offsets,
+            duration_tolerance: self
+                .duration_tolerance
+                .or(spec.duration_tolerance),
+            loudness_target: self.loudness_target.or(spec.loudness_target),
+            audio_only: self.audio_only.or(spec.audio_only),
+            subtitle_map,
+            subtitles: self.subtitles.or(spec.subtitles),
+            metadata_source: self.metadata_source.or(spec.metadata_source),
+            tags,
+            video_codec: self.video_codec.or(spec.video_codec),
+            video_cpu_used: self.video_cpu_used.or(spec.video_cpu_used),
+            video_max_height: self.video_max_height.or(spec.video_max_height),
+            video_max_width: self.video_max_width.or(spec.video_max_width),
+            video_target_height: self
+                .video_target_height
+                .or(spec.video_target_height),
+            video_scaler: self.video_scaler.or(spec.video_scaler),
+            video_crf_mode: self.video_crf_mode.or(spec.video_crf_mode),
+            video_crf_band: self.video_crf_band.or(spec.video_crf_band),
+            video_vmaf_target: self
+                .video_vmaf_target
+                .or(spec.video_vmaf_target),
+            video_target_size: self
+                .video_target_size
+                .or(spec.video_target_size),
+            video_max_bitrate: self
+                .video_max_bitrate
+                .or(spec.video_max_bitrate),
+            video_buffer_size: self
+                .video_buffer_size
+                .or(spec.video_buffer_size),
+            video_keyframe_interval: self
+                .video_keyframe_interval
+                .or(spec.video_keyframe_interval),
+            video_scene_cut: self.video_scene_cut.or(spec.video_scene_cut),
+            video_ladder: self.video_ladder.or(spec.video_ladder),
+            video_segments: self.video_segments.or(spec.video_segments),
+            video_container: self.video_container.or(spec.video_container),
+            video_burn_subtitles: self
+                .video_burn_subtitles
+                .or(spec.video_burn_subtitles),
+            targets,
+            preset: self.preset.or(spec.preset),
+        }
+    }
+
+    /// Resolve the settings of the job, using the defaults for everything that
+    /// has not been requested.
+    pub(crate) fn job_settings(&self) -> JobSettings {
+        let mut settings = JobSettings::default();
+
+        settings.audio.tolerance = self.loudness_tolerance;
+        if let Some(mode) = self.loudness_mode {
+            settings.audio.loudness_mode = mode;
+        }
+        settings.audio.opus = self.audio_opus.clone();
+        settings.audio.track_opus = self.track_opus.clone();
+        settings.audio.track_options = self.track_options.clone();
+        if let Some(stereo_downmix) = self.stereo_downmix {
+            settings.audio.stereo_downmix = stereo_downmix;
+        }
+        if let Some(container) = self.audio_only_container {
+            settings.audio_only_container = container;
+        }
+        settings.audio.input_offsets = self.input_offsets.clone();
+        if let Some(tolerance) = self.duration_tolerance {
+            settings.audio.duration_tolerance = tolerance;
+        }
+        if let Some(target) = self.loudness_target {
+            settings.audio.target_i = target;
+        }
+        if let Some(codec) = self.video_codec {
+            settings.video.codec = codec;
+        }
+        settings.video.cpu_used = self.video_cpu_used;
+        settings.video.max_height = self.video_max_height;
+        settings.video.max_width = self.video_max_width;
+        settings.video.target_height = self.video_target_height;
+        if let Some(scaler) = self.video_scaler {
+            settings.video.scaler = scaler;
+        }
+        if let Some(mode) = self.video_crf_mode {
+            settings.video.crf_mode = mode;
+        }
+        if let Some(band) = self.video_crf_band {
+            settings.video.crf_band = band;
+        }
+        if let Some(target) = self.video_vmaf_target {
+            settings.video.vmaf_target = target;
+        }
+        settings.video.target_size = self.video_target_size;
+        settings.video.max_bitrate = self.video_max_bitrate;
+        settings.video.buffer_size = self.video_buffer_size;
+        settings.video.keyframe_interval = self.video_keyframe_interval;
+        if let Some(scene_cut) = self.video_scene_cut {
+            settings.video.scene_cut = scene_cut;
+        }
+        settings.video.ladder = self.video_ladder.clone().unwrap_or_default();
+        if let Some(segments) = self.video_segments {
+            settings.video.segments = segments;
+        }
+        settings.video.container = self.video_container;
+        settings.video.burn_subtitles = self
+            .video_burn_subtitles
+            .map(|(file, stream)| SubtitleSource { file, stream });
+        settings.video.disabled = self.audio_only.unwrap_or(false);
+        if let Some(subtitles) = self.subtitles {
+            settings.keep_subtitles = subtitles;
+        }
+        settings.metadata.source = self.metadata_source;
+        settings.metadata.tags = self.tags.clone();
+
+        // a target takes the video settings of the main output that it does
+        // not override, other than the ladder and the container, which
+        // follows the codec of the target unless the target sets its own
+        let main = QueryStringContents {
+            video_ladder: None,
+            video_container: None,
+            targets: BTreeMap::new(),
+            ..self.clone()
+        };
+        settings.targets = self
+            .targets
+            .iter()
+            .map(|(name, target)| OutputTarget {
+                name: name.clone(),
+                video: target
+                    .clone()
+                    .overriding(main.clone())
+                    .job_settings()
+                    .video,
+            })
+            .collect();
+
+        settings
+    }
+}
+
+/// Parse the query string of an upload.
+///
+/// Unrecognized keys are ignored unless `strict=true` is given, in which case
+/// they are all rejected together.
 pub(crate) fn get_requests<'a>(
     params: &'a str,
 ) -> Result<QueryStringContents, QueryStringErrorSource<'a>> {
     let mut audios = HashMap::new();
+    let mut loudness_tolerance = None;
+    let mut loudness_mode = None;
+    let mut audio_opus = OpusSettings::default();
+    let mut track_opus = HashMap::new();
+    let mut track_options = HashMap::new();
+    let mut stereo_downmix = None;
+    let mut audio_only_container = None;
+    let mut input_offsets = HashMap::new();
+    let mut duration_tolerance = None;
+    let mut loudness_target = None;
+    let mut audio_only = None;
+    let mut subtitle_map = HashMap::new();
+    let mut subtitles = None;
+    let mut metadata_source = None;
+    let mut tags = BTreeMap::new();
+    let mut video_codec = None;
+    let mut video_cpu_used = None;
+    let mut video_max_height = None;
+    let mut video_max_width = None;
+    let mut video_target_height = None;
+    let mut video_scaler = None;
+    let mut video_crf_mode = None;
+    let mut video_crf_band = None;
+    let mut video_vmaf_target = None;
+    let mut video_target_size = None;
+    let mut video_max_bitrate = None;
+    let mut video_buffer_size = None;
+    let mut video_keyframe_interval = None;
+    let mut video_scene_cut = None;
+    let mut video_ladder = None;
+    let mut video_segments = None;
+    let mut video_container = None;
+    let mut video_burn_subtitles = None;
+    let mut targets = BTreeMap::new();
+    let mut preset = None;
+    let mut strict = false;
+    let mut unknown_keys = vec![];
 
     for (key, value) in querystring::querify(params).into_iter() {
-        let key_parts = key.split("_");
-
         match (key, value) {
+            // this has to go before the rest of the `audio_` keys
+            ("audio_only_container", v) => {
+                audio_only_container = Some(match v {
+                    "opus" => AudioOnlyContainer::Opus,
+                    "ogg" => AudioOnlyContainer::Ogg,
+                    "webm" => AudioOnlyContainer::WebM,
+                    _ => {
+                        return Err(QueryStringErrorSource::AudioOnlyContainer(
+                            v,
+                        ))
+                    },
+                });
+            },
+
+            ("audio_only", v) => {
+                audio_only = Some(
+                    v.parse::<bool>()
+                        .map_err(|_| QueryStringErrorSource::AudioOnly(v))?,
+                );
+            },
+
             (a, v) if a.starts_with("audio_") => {
-                let (a, o) = get_audio_query_parameter(a, v)?;
-                audios.insert(a, o);
+                let rest = &a["audio_".len() ..];
+
+                match rest.split_once('_') {
+                    // settings for a specific track, like `audio_0_bitrate`
+                    Some((track, setting))
+                        if track.parse::<usize>().is_ok() =>
+                    {
+                        let track = track.parse::<usize>().unwrap();
+                        let opus = track_opus
+                            .entry(track)
+                            .or_insert_with(OpusSettings::default);
+                        let options = track_options
+                            .entry(track)
+                            .or_insert_with(AudioTrackOptions::default);
+
+                        if !set_opus_setting(opus, setting, v)?
+                            && !set_track_option(options, setting, v)?
+                        {
+                            unknown_keys.push(a);
+                        }
+                    },
+
+                    // settings for all tracks, like `audio_bitrate`
+                    _ if set_opus_setting(&mut audio_opus, rest, v)? => {},
+
+                    _ => {
+                        let (a, o) = get_audio_query_parameter(a, v)?;
+                        audios.insert(a, o);
+                    },
+                }
+            },
+
+            // a subtitle track from a single source, like `subtitle_0=1:0`
+            (s, v) if s.starts_with("subtitle_") => {
+                let track_str = &s["subtitle_".len() ..];
+                let track = track_str.parse::<usize>().map_err(|e| {
+                    QueryStringErrorSource::SubtitleKey(track_str, *e.kind())
+                })?;
+
+                subtitle_map.insert(track, get_subtitle_source(v)?);
+            },
+
+            ("subtitles", v) => {
+                subtitles = Some(
+                    v.parse::<bool>()
+                        .map_err(|_| QueryStringErrorSource::Subtitles(v))?,
+                );
+            },
+
+            ("metadata_source", v) => {
+                metadata_source = Some(v.parse::<usize>().map_err(|e| {
+                    QueryStringErrorSource::MetadataSource(v, *e.kind())
+                })?);
+            },
+
+            // a global tag of the output, like `tag_title=Episode%201`
+            (t, v) if t.starts_with("tag_") => {
+                let name = &t["tag_".len() ..];
+                if !is_tag_name(name) {
+                    return Err(QueryStringErrorSource::TagName(name));
+                }
+
+                // like titles, tags are free text and are sent
+                // percent-encoded
+                let value = decode_free_text(v)
+                    .ok_or(QueryStringErrorSource::TagValue(v))?;
+
+                tags.insert(name.to_owned(), value);
+            },
+
+            ("loudness_tolerance", v) => {
+                loudness_tolerance = Some(get_loudness_tolerance(v)?);
             },
 
-            (key, _) => {
-                eprintln!("Unrecognized query key `{}`", key);
+            ("loudness_mode", v) => {
+                loudness_mode = Some(get_loudness_mode(v)?);
             },
+
+            ("loudness_target", v) => {
+                loudness_target = Some(get_loudness_target(v)?);
+            },
+
+            ("video_codec", v) => {
+                video_codec = Some(
+                    get_video_codec(v)
+                        .ok_or(QueryStringErrorSource::VideoCodec(v))?,
+                );
+            },
+
+            // an extra target in another codec, like `target_fallback=h264`,
+            // optionally in another container, like `target_fallback=h264:mkv`
+            (t, v) if t.starts_with("target_") => {
+                let name = &t["target_".len() ..];
+                if !is_target_name(name) {
+                    return Err(QueryStringErrorSource::TargetName(name));
+                }
+
+                let (codec, container) = match v.split_once(':') {
+                    Some((codec, container)) => (codec, Some(container)),
+                    None => (v, None),
+                };
+                let codec = get_video_codec(codec)
+                    .ok_or(QueryStringErrorSource::TargetCodec(codec))?;
+                let container = container
+                    .map(|c| {
+                        get_video_container(c)
+                            .ok_or(QueryStringErrorSource::VideoContainer(c))
+                    })
+                    .transpose()?;
+                targets.insert(
+                    name.to_owned(),
+                    QueryStringContents {
+                        video_codec: Some(codec),
+                        video_container: container,
+                        ..QueryStringContents::default()
+                    },
+                );
+            },
+
+            ("video_cpu_used", v) => {
+                video_cpu_used = Some(
+                    v.parse::<u8>()
+                        .ok()
+                        .and_then(|c| check_video_cpu_used(c).ok())
+                        .ok_or(QueryStringErrorSource::VideoCpuUsed(v))?,
+                );
+            },
+
+            ("video_max_height", v) => {
+                video_max_height = Some(get_video_max_height(v)?);
+            },
+
+            ("video_max_width", v) => {
+                video_max_width = Some(get_video_max_width(v)?);
+            },
+
+            ("video_target_height", v) => {
+                video_target_height = Some(get_video_target_height(v)?);
+            },
+
+            ("video_scaler", v) => {
+                video_scaler = Some(match v {
+                    "bilinear" => Scaler::Bilinear,
+                    "bicubic" => Scaler::Bicubic,
+                    "lanczos" => Scaler::Lanczos,
+                    "spline" => Scaler::Spline,
+                    "area" => Scaler::Area,
+                    _ => return Err(QueryStringErrorSource::VideoScaler(v)),
+                });
+            },
+
+            ("video_crf_mode", v) => {
+                video_crf_mode = Some(match v {
+                    "resolution" => CrfMode::Resolution,
+                    "content" => CrfMode::Content,
+                    "vmaf" => CrfMode::Vmaf,
+                    _ => return Err(QueryStringErrorSource::VideoCrfMode(v)),
+                });
+            },
+
+            ("video_crf_band", v) => {
+                video_crf_band = Some(get_video_crf_band(v)?);
+            },
+
+            ("video_vmaf_target", v) => {
+                video_vmaf_target = Some(get_video_vmaf_target(v)?);
+            },
+
+            ("video_target_size", v) => {
+                video_target_size = Some(get_video_target_size(v)?);
+            },
+
+            ("video_max_bitrate", v) => {
+                video_max_bitrate = Some(
+                    get_video_bitrate(v)
+                        .ok_or(QueryStringErrorSource::VideoMaxBitrate(v))?,
+                );
+            },
+
+            ("video_buffer_size", v) => {
+                video_buffer_size = Some(
+                    get_video_bitrate(v)
+                        .ok_or(QueryStringErrorSource::VideoBufferSize(v))?,
+                );
+            },
+
+            ("video_keyframe_interval", v) => {
+                video_keyframe_interval = Some(get_video_keyframe_interval(v)?);
+            },
+
+            ("video_ladder", v) => {
+                video_ladder = Some(
+                    v.split(['+', ','])
+                        .map(|h| {
+                            get_video_ladder_height(h)
+                                .ok_or(QueryStringErrorSource::VideoLadder(v))
+                        })
+                        .collect::<Result<Vec<_>, _>>()?,
+                );
+            },
+
+            ("video_segments", v) => {
+                video_segments = Some(match v {
+                    "mp4" => SegmentFormat::Mp4,
+                    "webm" => SegmentFormat::Webm,
+                    _ => return Err(QueryStringErrorSource::VideoSegments(v)),
+                });
+            },
+
+            ("video_container", v) => {
+                video_container = Some(
+                    get_video_container(v)
+                        .ok_or(QueryStringErrorSource::VideoContainer(v))?,
+                );
+            },
+
+            // a subtitle stream to burn in, like `video_burn_subtitles=1:0`
+            ("video_burn_subtitles", v) => {
+                video_burn_subtitles = Some(get_subtitle_source(v)?);
+            },
+
+            ("video_scene_cut", v) => {
+                video_scene_cut =
+                    Some(v.parse::<bool>().map_err(|_| {
+                        QueryStringErrorSource::VideoSceneCut(v)
+                    })?);
+            },
+
+            ("preset", v) => preset = Some(v.to_owned()),
+
+            ("stereo_downmix", v) => {
+                stereo_downmix =
+                    Some(v.parse::<bool>().map_err(|_| {
+                        QueryStringErrorSource::StereoDownmix(v)
+                    })?);
+            },
+
+            (o, v) if o.starts_with("offset_") => {
+                let file_str = &o["offset_".len() ..];
+                let file = file_str.parse::<usize>().map_err(|e| {
+                    QueryStringErrorSource::OffsetKey(file_str, *e.kind())
+                })?;
+
+                let offset = match v.parse::<f64>() {
+                    Ok(offset) if offset.is_finite() => offset,
+                    _ => return Err(QueryStringErrorSource::Offset(v)),
+                };
+
+                input_offsets.insert(file, offset);
+            },
+
+            ("duration_tolerance", v) => {
+                duration_tolerance = Some(
+                    v.parse::<f64>()
+                        .ok()
+                        .and_then(|t| check_tolerance(t).ok())
+                        .ok_or(QueryStringErrorSource::DurationTolerance(v))?,
+                );
+            },
+
+            ("strict", v) => {
+                strict = v
+                    .parse::<bool>()
+                    .map_err(|_| QueryStringErrorSource::Strict(v))?;
+            },
+
+            (key, _) => unknown_keys.push(key),
         }
     }
 
+    if strict && !unknown_keys.is_empty() {
+        return Err(QueryStringErrorSource::UnknownKeys(unknown_keys));
+    }
+
+    if video_buffer_size.is_some() && video_max_bitrate.is_none() {
+        return Err(QueryStringErrorSource::VideoBufferSizeWithoutMaxBitrate);
+    }
+
+    for key in unknown_keys {
+        eprintln!("Unrecognized query key `{}`", key);
+    }
+
     Ok(QueryStringContents {
         audio_map: audios,
+        loudness_tolerance,
+        loudness_mode,
+        audio_opus,
+        track_opus,
+        track_options,
+        stereo_downmix,
+        audio_only_container,
+        input_offsets,
+        duration_tolerance,
+        loudness_target,
+        audio_only,
+        subtitle_map,
+        subtitles,
+        metadata_source,
+        tags,
+        video_codec,
+        video_cpu_used,
+        video_max_height,
+        video_max_width,
+        video_target_height,
+        video_scaler,
+        video_crf_mode,
+        video_crf_band,
+        video_vmaf_target,
+        video_target_size,
+        video_max_bitrate,
+        video_buffer_size,
+        video_keyframe_interval,
+        video_scene_cut,
+        video_ladder,
+        video_segments,
+        video_container,
+        video_burn_subtitles,
+        targets,
+        preset,
     })
 }
 
+/// Set the Opus setting named `setting` from the query string value.
+///
+/// Returns `false` if there is no such setting.
+fn set_opus_setting<'a>(
+    opus: &mut OpusSettings,
+    setting: &str,
+    value: &'a str,
+) -> Result<bool, QueryStringErrorSource<'a>> {
+    match setting {
+        "bitrate" => opus.bitrate = Some(get_audio_bitrate(value)?),
+
+        "vbr" => {
+            opus.vbr = Some(match value {
+                "on" => OpusVbr::On,
+                "off" => OpusVbr::Off,
+                "constrained" => OpusVbr::Constrained,
+                _ => return Err(QueryStringErrorSource::AudioVbr(value)),
+            })
+        },
+
+        "application" => {
+            opus.application = Some(match value {
+                "audio" => OpusApplication::Audio,
+                "voip" => OpusApplication::Voip,
+                "lowdelay" => OpusApplication::LowDelay,
+                _ => {
+                    return Err(QueryStringErrorSource::AudioApplication(value))
+                },
+            })
+        },
+
+        "downmix" => {
+            opus.downmix = Some(
+                value
+                    .parse::<bool>()
+                    .map_err(|_| QueryStringErrorSource::AudioDownmix(value))?,
+            )
+        },
+
+        _ => return Ok(false),
+    }
+
+    Ok(true)
+}
+
+/// Set the track option named `setting` from the query string value.
+///
+/// Returns `false` if there is no such option.
+fn set_track_option<'a>(
+    options: &mut AudioTrackOptions,
+    setting: &str,
+    value: &'a str,
+) -> Result<bool, QueryStringErrorSource<'a>> {
+    match setting {
+        "lang" => {
+            if !is_language_tag(value) {
+                return Err(QueryStringErrorSource::AudioLanguage(value));
+            }
+
+            options.language = Some(value.to_string());
+        },
+
+        "title" => {
+            // unlike everything else, titles are free text and are sent
+            // percent-encoded
+            let title = decode_free_text(value)
+                .ok_or(QueryStringErrorSource::AudioTitle(value))?;
+
+            options.title = Some(title);
+        },
+
+        "default" => {
+            options.default = Some(
+                value
+                    .parse::<bool>()
+                    .map_err(|_| QueryStringErrorSource::AudioDefault(value))?,
+            )
+        },
+
+        "codec" => {
+            options.codec = Some(match value {
+                "auto" => AudioCodec::Auto,
+                "opus" => AudioCodec::Opus,
+                "copy" => AudioCodec::Copy,
+                _ => return Err(QueryStringErrorSource::AudioCodec(value)),
+            })
+        },
+
+        _ => return Ok(false),
+    }
+
+    Ok(true)
+}
+
+/// Whether the value looks like an ISO 639-2 code, like `eng`, or a BCP 47
+/// tag, like `en-US`.
+pub(crate) fn is_language_tag(value: &str) -> bool {
+    let mut parts = value.split('-');
+
+    let primary = parts.next().unwrap_or("");
+    let primary_valid = (2 ..= 3).contains(&primary.len())
+        && primary.chars().all(|c| c.is_ascii_alphabetic());
+
+    primary_valid
+        && parts.all(|p| {
+            (1 ..= 8).contains(&p.len())
+                && p.chars().all(|c| c.is_ascii_alphanumeric())
+        })
+}
+
+fn get_subtitle_source(
+    value: &str
+) -> Result<(usize, usize), QueryStringErrorSource<'_>> {
+    value
+        .split_once(':')
+        .and_then(|(file, stream)| {
+            Some((file.parse::<usize>().ok()?, stream.parse::<usize>().ok()?))
+        })
+        .ok_or(QueryStringErrorSource::SubtitleSource(value))
+}
+
+/// Whether the value can name a target, which it is downloaded by.
+pub(crate) fn is_target_name(value: &str) -> bool {
+    (1 ..= 32).contains(&value.len())
+        && value.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
+
+/// Whether the value can name a global tag of the output.
+pub(crate) fn is_tag_name(value: &str) -> bool {
+    (1 ..= 64).contains(&value.len())
+        && value.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Decode a free-text value, like a title or a tag. As in HTML forms, a `+` is
+/// a space and a literal plus is sent as `%2B`.
+fn decode_free_text(value: &str) -> Option<String> {
+    percent_decode_str(&value.replace('+', " "))
+        .decode_utf8()
+        .ok()
+        .map(|text| text.into_owned())
+}
+
+fn get_video_codec(value: &str) -> Option<VideoCodec> {
+    match value {
+        "av1" => Some(VideoCodec::Av1),
+        "vp9" => Some(VideoCodec::Vp9),
+        "h264" => Some(VideoCodec::H264),
+        _ => None,
+    }
+}
+
+fn get_video_container(value: &str) -> Option<Container> {
+    match value {
+        "webm" => Some(Container::WebM),
+        "mkv" | "matroska" => Some(Container::Matroska),
+        "mp4" => Some(Container::Mp4),
+        _ => None,
+    }
+}
+
+/// A setting whose value is out of its range. The query string and the job
+/// spec each describe the range in their own errors.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) struct OutOfRange;
+
+pub(crate) fn check_audio_bitrate(value: u32) -> Result<u32, OutOfRange> {
+    match value {
+        b if b >= 500 => Ok(b),
+        _ => Err(OutOfRange),
+    }
+}
+
+/// Check a tolerance, which has to be a finite non-negative number.
+pub(crate) fn check_tolerance(value: f64) -> Result<f64, OutOfRange> {
+    match value {
+        t if t.is_finite() && t >= 0. => Ok(t),
+        _ => Err(OutOfRange),
+    }
+}
+
+pub(crate) fn check_loudness_target(value: f64) -> Result<f64, OutOfRange> {
+    // the range that loudnorm accepts
+    match value {
+        t if (-70. ..= -5.).contains(&t) => Ok(t),
+        _ => Err(OutOfRange),
+    }
+}
+
+pub(crate) fn check_video_cpu_used(value: u8) -> Result<u8, OutOfRange> {
+    match value {
+        c if c <= 8 => Ok(c),
+        _ => Err(OutOfRange),
+    }
+}
+
+/// Check a height or a width of the video, which has to be positive and
+/// even.
+pub(crate) fn check_video_dimension(value: usize) -> Result<usize, OutOfRange> {
+    match value {
+        d if d > 0 && d % 2 == 0 => Ok(d),
+        _ => Err(OutOfRange),
+    }
+}
+
+pub(crate) fn check_video_crf_band(value: usize) -> Result<usize, OutOfRange> {
+    match value {
+        b if b <= 63 => Ok(b),
+        _ => Err(OutOfRange),
+    }
+}
+
+pub(crate) fn check_video_vmaf_target(value: f64) -> Result<f64, OutOfRange> {
+    // VMAF scores go from 0 to 100
+    match value {
+        t if t > 0. && t <= 100. => Ok(t),
+        _ => Err(OutOfRange),
+    }
+}
+
+pub(crate) fn check_video_target_size(
+    value: usize
+) -> Result<usize, OutOfRange> {
+    match value {
+        s if s > 0 => Ok(s),
+        _ => Err(OutOfRange),
+    }
+}
+
+/// Check a number of bits, or bits per second, of the video.
+pub(crate) fn check_video_bitrate(value: u64) -> Result<u64, OutOfRange> {
+    match value {
+        b if b > 0 => Ok(b),
+        _ => Err(OutOfRange),
+    }
+}
+
+pub(crate) fn check_video_keyframe_interval(
+    value: f64
+) -> Result<f64, OutOfRange> {
+    match value {
+        i if i.is_finite() && i > 0. => Ok(i),
+        _ => Err(OutOfRange),
+    }
+}
+
+pub(crate) fn get_audio_bitrate(
+    value: &str
+) -> Result<u32, QueryStringErrorSource<'_>> {
+    let (number, multiplier) = match value.strip_suffix('k') {
+        Some(number) => (number, 1000),
+        None => (value, 1),
+    };
+
+    number
+        .parse::<u32>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .and_then(|n| check_audio_bitrate(n).ok())
+        .ok_or(QueryStringErrorSource::AudioBitrate(value))
+}
+
+fn get_loudness_tolerance(
+    value: &str
+) -> Result<f64, QueryStringErrorSource<'_>> {
+    value
+        .parse::<f64>()
+        .ok()
+        .and_then(|t| check_tolerance(t).ok())
+        .ok_or(QueryStringErrorSource::LoudnessTolerance(value))
+}
+
+fn get_loudness_target(value: &str) -> Result<f64, QueryStringErrorSource<'_>> {
+    value
+        .parse::<f64>()
+        .ok()
+        .and_then(|t| check_loudness_target(t).ok())
+        .ok_or(QueryStringErrorSource::LoudnessTarget(value))
+}
+
+fn get_video_max_height(
+    value: &str
+) -> Result<usize, QueryStringErrorSource<'_>> {
+    value
+        .parse::<usize>()
+        .ok()
+        .and_then(|h| check_video_dimension(h).ok())
+        .ok_or(QueryStringErrorSource::VideoMaxHeight(value))
+}
+
+fn get_video_max_width(
+    value: &str
+) -> Result<usize, QueryStringErrorSource<'_>> {
+    value
+        .parse::<usize>()
+        .ok()
+        .and_then(|w| check_video_dimension(w).ok())
+        .ok_or(QueryStringErrorSource::VideoMaxWidth(value))
+}
+
+fn get_video_target_height(
+    value: &str
+) -> Result<usize, QueryStringErrorSource<'_>> {
+    value
+        .parse::<usize>()
+        .ok()
+        .and_then(|h| check_video_dimension(h).ok())
+        .ok_or(QueryStringErrorSource::VideoTargetHeight(value))
+}
+
+fn get_video_crf_band(
+    value: &str
+) -> Result<usize, QueryStringErrorSource<'_>> {
+    value
+        .parse::<usize>()
+        .ok()
+        .and_then(|b| check_video_crf_band(b).ok())
+        .ok_or(QueryStringErrorSource::VideoCrfBand(value))
+}
+
+fn get_video_vmaf_target(
+    value: &str
+) -> Result<f64, QueryStringErrorSource<'_>> {
+    value
+        .parse::<f64>()
+        .ok()
+        .and_then(|t| check_video_vmaf_target(t).ok())
+        .ok_or(QueryStringErrorSource::VideoVmafTarget(value))
+}
+
+fn get_video_target_size(
+    value: &str
+) -> Result<usize, QueryStringErrorSource<'_>> {
+    parse_size(value)
+        .ok()
+        .and_then(|s| check_video_target_size(s).ok())
+        .ok_or(QueryStringErrorSource::VideoTargetSize(value))
+}
+
+/// Parse a positive number of bits, or bits per second, optionally suffixed
+/// with `k` or `M`.
+pub(crate) fn get_video_bitrate(value: &str) -> Option<u64> {
+    let (number, multiplier) = if let Some(number) = value.strip_suffix('k') {
+        (number, 1_000)
+    }
+    else if let Some(number) = value.strip_suffix('M') {
+        (number, 1_000_000)
+    }
+    else {
+        (value, 1)
+    };
+
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .and_then(|n| check_video_bitrate(n).ok())
+}
+
+/// Parse the height of a rendition of a ladder, which has to be positive and
+/// even.
+fn get_video_ladder_height(value: &str) -> Option<usize> {
+    value
+        .parse::<usize>()
+        .ok()
+        .and_then(|h| check_video_dimension(h).ok())
+}
+
+fn get_video_keyframe_interval(
+    value: &str
+) -> Result<f64, QueryStringErrorSource<'_>> {
+    value
+        .parse::<f64>()
+        .ok()
+        .and_then(|i| check_video_keyframe_interval(i).ok())
+        .ok_or(QueryStringErrorSource::VideoKeyframeInterval(value))
+}
+
+fn get_loudness_mode(
+    value: &str
+) -> Result<LoudnessMode, QueryStringErrorSource<'_>> {
+    match value {
+        "normalize" => Ok(LoudnessMode::Normalize),
+        "r128" => Ok(LoudnessMode::R128Tags),
+        "replaygain" => Ok(LoudnessMode::ReplayGainTags),
+        _ => Err(QueryStringErrorSource::LoudnessMode(value)),
+    }
+}
+
 fn get_audio_query_parameter<'a>(
     audio_key: &'a str,
     value: &'a str,
@@ -127,7 +1581,9 @@ fn get_audio_query_parameter<'a>(
 
     let mut ordering = vec![];
 
-    for part in value.split(",") {
+    // the sources are separated by `+`, but `,` is still accepted since it
+    // used to be the only separator
+    for part in value.split(['+', ',']) {
         let mut part_part = part.split(":");
 
         let source_file_str = part_part
@@ -149,3 +1605,161 @@ fn get_audio_query_parameter<'a>(
 
     Ok((target_index, ordering))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn audio_sources_are_separated_by_plus() {
+        let qsc = get_requests("audio_0=0:0+1:0&audio_1=1:1").unwrap();
+
+        assert_eq!(qsc.audio_map[&0], vec![(0, 0), (1, 0)]);
+        assert_eq!(qsc.audio_map[&1], vec![(1, 1)]);
+    }
+
+    #[test]
+    fn audio_sources_are_still_separated_by_comma() {
+        let qsc = get_requests("audio_0=0:0,1:0+2:1").unwrap();
+
+        assert_eq!(qsc.audio_map[&0], vec![(0, 0), (1, 0), (2, 1)]);
+    }
+
+    #[test]
+    fn audio_sources_reject_bad_indices() {
+        assert!(matches!(
+            get_requests("audio_0=0:0+x:1"),
+            Err(QueryStringErrorSource::AudioFileSource("x", _))
+        ));
+        assert!(matches!(
+            get_requests("audio_0=0"),
+            Err(QueryStringErrorSource::AudioChannelSource("", _))
+        ));
+    }
+
+    #[test]
+    fn per_track_keys_apply_to_their_track() {
+        let qsc = get_requests(
+            "audio_bitrate=128k&audio_1_bitrate=96k&audio_1_vbr=constrained&\
+             audio_1_lang=en-US&audio_1_title=Director%27s%20Cut&\
+             audio_1_default=true&audio_1_codec=copy",
+        )
+        .unwrap();
+
+        // per-track keys are not audio sources
+        assert!(qsc.audio_map.is_empty());
+        assert_eq!(qsc.audio_opus.bitrate, Some(128_000));
+
+        let opus = &qsc.track_opus[&1];
+        assert_eq!(opus.bitrate, Some(96_000));
+        assert_eq!(opus.vbr, Some(OpusVbr::Constrained));
+
+        let options = &qsc.track_options[&1];
+        assert_eq!(options.language.as_deref(), Some("en-US"));
+        assert_eq!(options.title.as_deref(), Some("Director's Cut"));
+        assert_eq!(options.default, Some(true));
+        assert_eq!(options.codec, Some(AudioCodec::Copy));
+
+        assert!(!qsc.track_opus.contains_key(&0));
+    }
+
+    #[test]
+    fn per_track_keys_are_checked() {
+        assert!(matches!(
+            get_requests("audio_0_lang=english"),
+            Err(QueryStringErrorSource::AudioLanguage("english"))
+        ));
+        assert!(matches!(
+            get_requests("audio_0_codec=mp3"),
+            Err(QueryStringErrorSource::AudioCodec("mp3"))
+        ));
+        assert!(matches!(
+            get_requests("audio_0_default=yes"),
+            Err(QueryStringErrorSource::AudioDefault("yes"))
+        ));
+    }
+
+    #[test]
+    fn strict_mode_rejects_unknown_keys() {
+        assert!(get_requests("audio_0_colour=red&foo=1").is_ok());
+
+        match get_requests("audio_0_colour=red&strict=true&foo=1") {
+            Err(QueryStringErrorSource::UnknownKeys(keys)) => {
+                assert_eq!(keys, vec!["audio_0_colour", "foo"]);
+            },
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn targets_take_an_optional_container() {
+        let qsc = get_requests(
+            "video_container=webm&target_fallback=h264&target_archive=av1:mkv",
+        )
+        .unwrap();
+        let settings = qsc.job_settings();
+
+        assert_eq!(settings.video.container, Some(Container::WebM));
+
+        let archive = &settings.targets[0];
+        assert_eq!(archive.name, "archive");
+        assert_eq!(archive.video.codec, VideoCodec::Av1);
+        assert_eq!(archive.video.container, Some(Container::Matroska));
+
+        // without a container of its own, a target follows its codec
+        let fallback = &settings.targets[1];
+        assert_eq!(fallback.name, "fallback");
+        assert_eq!(fallback.video.codec, VideoCodec::H264);
+        assert_eq!(fallback.video.container, None);
+    }
+
+    #[test]
+    fn targets_reject_bad_containers() {
+        assert!(matches!(
+            get_requests("target_fallback=h264:avi"),
+            Err(QueryStringErrorSource::VideoContainer("avi"))
+        ));
+        assert!(matches!(
+            get_requests("target_fallback=h265:mkv"),
+            Err(QueryStringErrorSource::TargetCodec("h265"))
+        ));
+    }
+
+    #[test]
+    fn checks_are_shared_with_the_job_spec() {
+        use crate::spec::get_spec;
+
+        assert!(get_requests("video_max_height=720").is_ok());
+        assert!(get_spec(r#"{ "video": { "max_height": 720 } }"#).is_ok());
+
+        assert!(matches!(
+            get_requests("video_max_height=721"),
+            Err(QueryStringErrorSource::VideoMaxHeight("721"))
+        ));
+        assert!(get_spec(r#"{ "video": { "max_height": 721 } }"#).is_err());
+
+        assert!(matches!(
+            get_requests("audio_bitrate=499"),
+            Err(QueryStringErrorSource::AudioBitrate("499"))
+        ));
+        assert!(get_spec(r#"{ "opus": { "bitrate": 499 } }"#).is_err());
+        assert!(get_spec(r#"{ "opus": { "bitrate": 4294967296 } }"#).is_err());
+    }
+
+    #[test]
+    fn free_text_decodes_plus_as_a_space() {
+        let qsc = get_requests(
+            "audio_0=0:0+1:0&audio_0_title=Director%27s+Cut&\
+             tag_comment=1%2B1+%3D+2",
+        )
+        .unwrap();
+
+        // audio sources still take `+` as a separator
+        assert_eq!(qsc.audio_map[&0], vec![(0, 0), (1, 0)]);
+        assert_eq!(
+            qsc.track_options[&0].title.as_deref(),
+            Some("Director's Cut")
+        );
+        assert_eq!(qsc.tags["comment"], "1+1 = 2");
+    }
+}
@@ -0,0 +1,672 @@
+use core::fmt::Write;
+use std::collections::{
+    BTreeMap,
+    HashMap,
+};
+
+use serde::{
+    Deserialize,
+    Deserializer,
+};
+
+use crate::{
+    config::size,
+    converter::{
+        AudioCodec,
+        AudioOnlyContainer,
+        AudioTrackOptions,
+        Container,
+        CrfMode,
+        LoudnessMode,
+        OpusApplication,
+        OpusSettings,
+        OpusVbr,
+        Scaler,
+        SegmentFormat,
+        VideoCodec,
+    },
+    query_string::{
+        check_audio_bitrate,
+        check_loudness_target,
+        check_tolerance,
+        check_video_bitrate,
+        check_video_cpu_used,
+        check_video_crf_band,
+        check_video_dimension,
+        check_video_keyframe_interval,
+        check_video_target_size,
+        check_video_vmaf_target,
+        get_audio_bitrate,
+        get_video_bitrate,
+        is_language_tag,
+        is_tag_name,
+        is_target_name,
+        QueryStringContents,
+    },
+};
+
+/// A job spec, sent as JSON alongside the uploaded files.
+///
+/// Every field is optional and mirrors a query string key:
+///
+/// ```json
+/// {
+///     "audio": { "0": [[0, 0], [1, 0]] },
+///     "loudness_mode": "r128",
+///     "opus": { "bitrate": "128k" },
+///     "tracks": { "0": { "lang": "eng", "title": "Main", "default": true } },
+///     "offsets": { "1": 0.5 },
+///     "subtitle": { "0": [0, 1] },
+///     "tags": { "title": "Episode 1", "comment": "" },
+///     "video": { "codec": "vp9", "max_height": 720 },
+///     "targets": {
+///         "fallback": { "codec": "h264", "burn_subtitles": [0, 0] }
+///     }
+/// }
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JobSpec {
+    /// The same as `preset`.
+    #[serde(default)]
+    preset: Option<String>,
+    /// The same as `audio_[n]`, with each source as a `[file, stream]` pair.
+    #[serde(default)]
+    audio: HashMap<usize, Vec<(usize, usize)>>,
+    #[serde(default, deserialize_with = "non_negative")]
+    loudness_tolerance: Option<f64>,
+    #[serde(default)]
+    loudness_mode: Option<LoudnessMode>,
+    #[serde(default, deserialize_with = "loudness_target")]
+    loudness_target: Option<f64>,
+    /// The same as the `audio_<setting>` keys.
+    #[serde(default)]
+    opus: OpusSpec,
+    /// The same as the `audio_[n]_<setting>` keys.
+    #[serde(default)]
+    tracks: HashMap<usize, TrackSpec>,
+    #[serde(default)]
+    stereo_downmix: Option<bool>,
+    #[serde(default)]
+    audio_only_container: Option<AudioOnlyContainer>,
+    /// The same as the `offset_[m]` keys.
+    #[serde(default)]
+    offsets: HashMap<usize, f64>,
+    #[serde(default, deserialize_with = "non_negative")]
+    duration_tolerance: Option<f64>,
+    #[serde(default)]
+    audio_only: Option<bool>,
+    /// The same as `subtitle_[n]`, with the source as a `[file, stream]`
+    /// pair.
+    #[serde(default)]
+    subtitle: HashMap<usize, (usize, usize)>,
+    #[serde(default)]
+    subtitles: Option<bool>,
+    #[serde(default)]
+    metadata_source: Option<usize>,
+    /// The same as the `tag_<name>` keys.
+    #[serde(default, deserialize_with = "tags")]
+    tags: BTreeMap<String, String>,
+    /// The same as the `video_<setting>` keys.
+    #[serde(default)]
+    video: VideoSpec,
+    /// The extra targets by their names, each with only the video settings
+    /// that differ from those of the main output.
+    #[serde(default, deserialize_with = "targets")]
+    targets: BTreeMap<String, VideoSpec>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct VideoSpec {
+    #[serde(default)]
+    codec: Option<VideoCodec>,
+    #[serde(default, deserialize_with = "cpu_used")]
+    cpu_used: Option<u8>,
+    #[serde(default, deserialize_with = "max_height")]
+    max_height: Option<usize>,
+    #[serde(default, deserialize_with = "max_width")]
+    max_width: Option<usize>,
+    #[serde(default, deserialize_with = "target_height")]
+    target_height: Option<usize>,
+    #[serde(default)]
+    scaler: Option<Scaler>,
+    #[serde(default)]
+    crf_mode: Option<CrfMode>,
+    #[serde(default, deserialize_with = "crf_band")]
+    crf_band: Option<usize>,
+    #[serde(default, deserialize_with = "vmaf_target")]
+    vmaf_target: Option<f64>,
+    /// In bytes, as a number or as a string like `"25M"`.
+    #[serde(default, deserialize_with = "target_size")]
+    target_size: Option<usize>,
+    /// In bits per second, as a number or as a string like `"4M"`.
+    #[serde(default, deserialize_with = "video_bitrate")]
+    max_bitrate: Option<u64>,
+    /// In bits, as a number or as a string like `"8M"`.
+    #[serde(default, deserialize_with = "video_bitrate")]
+    buffer_size: Option<u64>,
+    #[serde(default, deserialize_with = "keyframe_interval")]
+    keyframe_interval: Option<f64>,
+    #[serde(default)]
+    scene_cut: Option<bool>,
+    /// The heights of the renditions, like `[1080, 720, 480]`.
+    #[serde(default, deserialize_with = "ladder")]
+    ladder: Option<Vec<usize>>,
+    #[serde(default)]
+    segments: Option<SegmentFormat>,
+    #[serde(default)]
+    container: Option<Container>,
+    /// The source as a `[file, stream]` pair, like for `subtitle`.
+    #[serde(default)]
+    burn_subtitles: Option<(usize, usize)>,
+}
+
+impl VideoSpec {
+    /// The contents with only the video settings.
+    fn into_contents(self) -> QueryStringContents {
+        QueryStringContents {
+            video_codec: self.codec,
+            video_cpu_used: self.cpu_used,
+            video_max_height: self.max_height,
+            video_max_width: self.max_width,
+            video_target_height: self.target_height,
+            video_scaler: self.scaler,
+            video_crf_mode: self.crf_mode,
+            video_crf_band: self.crf_band,
+            video_vmaf_target: self.vmaf_target,
+            video_target_size: self.target_size,
+            video_max_bitrate: self.max_bitrate,
+            video_buffer_size: self.buffer_size,
+            video_keyframe_interval: self.keyframe_interval,
+            video_scene_cut: self.scene_cut,
+            video_ladder: self.ladder,
+            video_segments: self.segments,
+            video_container: self.container,
+            video_burn_subtitles: self.burn_subtitles,
+            ..QueryStringContents::default()
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct OpusSpec {
+    #[serde(default, deserialize_with = "bitrate")]
+    bitrate: Option<u32>,
+    #[serde(default)]
+    vbr: Option<OpusVbr>,
+    #[serde(default)]
+    application: Option<OpusApplication>,
+    #[serde(default)]
+    downmix: Option<bool>,
+}
+
+impl OpusSpec {
+    fn into_settings(self) -> OpusSettings {
+        OpusSettings {
+            bitrate: self.bitrate,
+            vbr: self.vbr,
+            application: self.application,
+            downmix: self.downmix,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TrackSpec {
+    #[serde(default, deserialize_with = "bitrate")]
+    bitrate: Option<u32>,
+    #[serde(default)]
+    vbr: Option<OpusVbr>,
+    #[serde(default)]
+    application: Option<OpusApplication>,
+    #[serde(default)]
+    downmix: Option<bool>,
+    #[serde(default, deserialize_with = "language")]
+    lang: Option<String>,
+    #[serde(default)]
+    title: Option<String>,
+    #[serde(default)]
+    default: Option<bool>,
+    #[serde(default)]
+    codec: Option<AudioCodec>,
+}
+
+impl JobSpec {
+    /// Check the fields that depend on each other, which serde cannot do
+    /// field by field. A target takes the maximum bitrate of the main output
+    /// if it does not have its own.
+    fn check(&self) -> Result<(), SpecError> {
+        let buffer_size_error = |path: String| SpecError {
+            path,
+            message: "buffer_size is only used with max_bitrate, which is not \
+                      given"
+                .to_owned(),
+        };
+
+        if self.video.buffer_size.is_some() && self.video.max_bitrate.is_none()
+        {
+            return Err(buffer_size_error("video.buffer_size".to_owned()));
+        }
+
+        for (name, target) in self.targets.iter() {
+            let max_bitrate = target.max_bitrate.or(self.video.max_bitrate);
+            if target.buffer_size.is_some() && max_bitrate.is_none() {
+                return Err(buffer_size_error(format!(
+                    "targets.{}.buffer_size",
+                    name
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn into_contents(self) -> QueryStringContents {
+        let mut track_opus = HashMap::new();
+        let mut track_options = HashMap::new();
+
+        for (track, spec) in self.tracks {
+            track_opus.insert(
+                track,
+                OpusSettings {
+                    bitrate: spec.bitrate,
+                    vbr: spec.vbr,
+                    application: spec.application,
+                    downmix: spec.downmix,
+                },
+            );
+            track_options.insert(
+                track,
+                AudioTrackOptions {
+                    language: spec.lang,
+                    title: spec.title,
+                    default: spec.default,
+                    codec: spec.codec,
+                },
+            );
+        }
+
+        QueryStringContents {
+            audio_map: self.audio,
+            loudness_tolerance: self.loudness_tolerance,
+            loudness_mode: self.loudness_mode,
+            audio_opus: self.opus.into_settings(),
+            track_opus,
+            track_options,
+            stereo_downmix: self.stereo_downmix,
+            audio_only_container: self.audio_only_container,
+            input_offsets: self.offsets,
+            duration_tolerance: self.duration_tolerance,
+            loudness_target: self.loudness_target,
+            audio_only: self.audio_only,
+            subtitle_map: self.subtitle,
+            subtitles: self.subtitles,
+            metadata_source: self.metadata_source,
+            tags: self.tags,
+            targets: self
+                .targets
+                .into_iter()
+                .map(|(name, spec)| (name, spec.into_contents()))
+                .collect(),
+            preset: self.preset,
+            ..self.video.into_contents()
+        }
+    }
+}
+
+fn non_negative<'de, D>(deserializer: D) -> Result<Option<f64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Option::<f64>::deserialize(deserializer)? {
+        Some(value) if check_tolerance(value).is_err() => {
+            Err(serde::de::Error::custom(format!(
+                "invalid value {}, expected a non-negative number",
+                value
+            )))
+        },
+        value => Ok(value),
+    }
+}
+
+fn bitrate<'de, D>(deserializer: D) -> Result<Option<u32>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    // bitrates can be given as a number or as a string like in the query
+    // string, like `"128k"`
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Bitrate {
+        Number(u64),
+        String(String),
+    }
+
+    let (bitrate, value) = match Option::<Bitrate>::deserialize(deserializer)? {
+        None => return Ok(None),
+        Some(Bitrate::Number(n)) => (
+            u32::try_from(n)
+                .ok()
+                .and_then(|n| check_audio_bitrate(n).ok()),
+            n.to_string(),
+        ),
+        Some(Bitrate::String(s)) => (get_audio_bitrate(&s).ok(), s),
+    };
+
+    bitrate.map(Some).ok_or_else(|| {
+        serde::de::Error::custom(format!(
+            "invalid bitrate \"{}\", expected bits per second of at least \
+             500, optionally suffixed with \"k\"",
+            value
+        ))
+    })
+}
+
+fn loudness_target<'de, D>(deserializer: D) -> Result<Option<f64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Option::<f64>::deserialize(deserializer)? {
+        Some(value) if check_loudness_target(value).is_err() => {
+            Err(serde::de::Error::custom(format!(
+                "invalid loudness target {}, expected LUFS from -70 to -5",
+                value
+            )))
+        },
+        value => Ok(value),
+    }
+}
+
+fn cpu_used<'de, D>(deserializer: D) -> Result<Option<u8>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Option::<u8>::deserialize(deserializer)? {
+        Some(value) if check_video_cpu_used(value).is_err() => {
+            Err(serde::de::Error::custom(format!(
+                "invalid CPU used {}, expected an integer from 0 to 8",
+                value
+            )))
+        },
+        value => Ok(value),
+    }
+}
+
+fn max_height<'de, D>(deserializer: D) -> Result<Option<usize>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Option::<usize>::deserialize(deserializer)? {
+        Some(value) if check_video_dimension(value).is_err() => {
+            Err(serde::de::Error::custom(format!(
+                "invalid maximum height {}, expected a positive even integer",
+                value
+            )))
+        },
+        value => Ok(value),
+    }
+}
+
+fn max_width<'de, D>(deserializer: D) -> Result<Option<usize>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Option::<usize>::deserialize(deserializer)? {
+        Some(value) if check_video_dimension(value).is_err() => {
+            Err(serde::de::Error::custom(format!(
+                "invalid maximum width {}, expected a positive even integer",
+                value
+            )))
+        },
+        value => Ok(value),
+    }
+}
+
+fn target_height<'de, D>(deserializer: D) -> Result<Option<usize>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Option::<usize>::deserialize(deserializer)? {
+        Some(value) if check_video_dimension(value).is_err() => {
+            Err(serde::de::Error::custom(format!(
+                "invalid target height {}, expected a positive even integer",
+                value
+            )))
+        },
+        value => Ok(value),
+    }
+}
+
+fn crf_band<'de, D>(deserializer: D) -> Result<Option<usize>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Option::<usize>::deserialize(deserializer)? {
+        Some(value) if check_video_crf_band(value).is_err() => {
+            Err(serde::de::Error::custom(format!(
+                "invalid CRF band {}, expected an integer from 0 to 63",
+                value
+            )))
+        },
+        value => Ok(value),
+    }
+}
+
+fn vmaf_target<'de, D>(deserializer: D) -> Result<Option<f64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Option::<f64>::deserialize(deserializer)? {
+        Some(value) if check_video_vmaf_target(value).is_err() => {
+            Err(serde::de::Error::custom(format!(
+                "invalid VMAF target {}, expected a number above 0 and up to \
+                 100",
+                value
+            )))
+        },
+        value => Ok(value),
+    }
+}
+
+fn target_size<'de, D>(deserializer: D) -> Result<Option<usize>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match size(deserializer)? {
+        Some(value) if check_video_target_size(value).is_err() => {
+            Err(serde::de::Error::custom(format!(
+                "invalid target size {}, expected a positive number of bytes",
+                value
+            )))
+        },
+        value => Ok(value),
+    }
+}
+
+fn video_bitrate<'de, D>(deserializer: D) -> Result<Option<u64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    // the same as bitrates of the audio
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Bitrate {
+        Number(u64),
+        String(String),
+    }
+
+    let (bitrate, value) = match Option::<Bitrate>::deserialize(deserializer)? {
+        None => return Ok(None),
+        Some(Bitrate::Number(n)) => {
+            (check_video_bitrate(n).ok(), n.to_string())
+        },
+        Some(Bitrate::String(s)) => (get_video_bitrate(&s), s),
+    };
+
+    bitrate.map(Some).ok_or_else(|| {
+        serde::de::Error::custom(format!(
+            "invalid bitrate \"{}\", expected a positive number optionally \
+             suffixed with \"k\" or \"M\"",
+            value
+        ))
+    })
+}
+
+fn keyframe_interval<'de, D>(deserializer: D) -> Result<Option<f64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Option::<f64>::deserialize(deserializer)? {
+        Some(value) if check_video_keyframe_interval(value).is_err() => {
+            Err(serde::de::Error::custom(format!(
+                "invalid keyframe interval {}, expected a positive number of \
+                 seconds",
+                value
+            )))
+        },
+        value => Ok(value),
+    }
+}
+
+fn ladder<'de, D>(deserializer: D) -> Result<Option<Vec<usize>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let heights = match Option::<Vec<usize>>::deserialize(deserializer)? {
+        None => return Ok(None),
+        Some(heights) => heights,
+    };
+
+    match heights.iter().find(|&&h| check_video_dimension(h).is_err()) {
+        Some(height) => Err(serde::de::Error::custom(format!(
+            "invalid rendition height {}, expected a positive even integer",
+            height
+        ))),
+        None => Ok(Some(heights)),
+    }
+}
+
+fn targets<'de, D>(
+    deserializer: D
+) -> Result<BTreeMap<String, VideoSpec>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let targets = BTreeMap::<String, VideoSpec>::deserialize(deserializer)?;
+
+    for (name, spec) in targets.iter() {
+        if !is_target_name(name) {
+            return Err(serde::de::Error::custom(format!(
+                "invalid target name \"{}\", expected up to 32 letters, \
+                 digits or dashes",
+                name
+            )));
+        }
+
+        if spec.ladder.is_some() || spec.segments.is_some() {
+            return Err(serde::de::Error::custom(format!(
+                "target \"{}\" has a ladder, which only the main output can \
+                 have",
+                name
+            )));
+        }
+    }
+
+    Ok(targets)
+}
+
+fn tags<'de, D>(deserializer: D) -> Result<BTreeMap<String, String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let tags = BTreeMap::<String, String>::deserialize(deserializer)?;
+
+    if let Some(name) = tags.keys().find(|name| !is_tag_name(name)) {
+        return Err(serde::de::Error::custom(format!(
+            "invalid tag name \"{}\", expected up to 64 letters, digits or \
+             underscores",
+            name
+        )));
+    }
+
+    Ok(tags)
+}
+
+fn language<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Option::<String>::deserialize(deserializer)? {
+        Some(value) if !is_language_tag(&value) => {
+            Err(serde::de::Error::custom(format!(
+                "invalid language \"{}\", expected an ISO 639-2 code or a \
+                 BCP 47 tag",
+                value
+            )))
+        },
+        value => Ok(value),
+    }
+}
+
+/// An error from parsing a job spec.
+#[derive(Debug, Clone)]
+pub struct SpecError {
+    /// Where in the spec the error is, like `tracks.0.lang`.
+    path: String,
+    message: String,
+}
+
+impl SpecError {
+    pub fn write_error_msg<W>(
+        &self,
+        writer: &mut W,
+    ) -> Result<(), std::fmt::Error>
+    where
+        W: Write,
+    {
+        write!(
+            writer,
+            "Unable to parse job spec at \"{}\": {}",
+            self.path, self.message
+        )
+    }
+
+    pub fn as_error_msg(&self) -> String {
+        let mut retval = String::new();
+        self.write_error_msg(&mut retval).unwrap();
+        retval
+    }
+}
+
+/// Parse a JSON job spec into the same contents as a query string.
+pub(crate) fn get_spec(json: &str) -> Result<QueryStringContents, SpecError> {
+    let deserializer = &mut serde_json::Deserializer::from_str(json);
+
+    let spec = serde_path_to_error::deserialize::<_, JobSpec>(deserializer)
+        .map_err(spec_error)?;
+    spec.check()?;
+    Ok(spec.into_contents())
+}
+
+/// Parse an already read JSON job spec into the same contents as a query
+/// string.
+pub(crate) fn get_spec_value(
+    value: serde_json::Value
+) -> Result<QueryStringContents, SpecError> {
+    let spec = serde_path_to_error::deserialize::<_, JobSpec>(value)
+        .map_err(spec_error)?;
+    spec.check()?;
+    Ok(spec.into_contents())
+}
+
+fn spec_error(
+    error: serde_path_to_error::Error<serde_json::Error>
+) -> SpecError {
+    SpecError {
+        path: error.path().to_string(),
+        message: error.into_inner().to_string(),
+    }
+}